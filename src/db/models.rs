@@ -28,10 +28,98 @@ pub struct UserAuthData {
     pub auth_until: NaiveDateTime,
 }
 
-impl<'a> From<&'a Token> for User {
-    fn from(token: &'a Token) -> Self {
-        // TODO
-        unimplemented!()
+/// Revoked token model for the "revoked_tokens" table
+///
+/// ```sql
+/// CREATE TABLE revoked_tokens (
+///     digest     BYTEA PRIMARY KEY,
+///     expires_at TIMESTAMP NOT NULL
+/// );
+/// ```
+#[derive(Debug, Clone)]
+pub struct RevokedToken {
+    /// SHA-256 of the raw token
+    pub digest: Vec<u8>,
+    /// Token expiration time: the record is useless afterwards
+    pub expires_at: NaiveDateTime,
+}
+
+/// Revoked user model for the "revoked_users" table
+///
+/// ```sql
+/// CREATE TABLE revoked_users (
+///     uid        TEXT PRIMARY KEY,
+///     revoked_at TIMESTAMP NOT NULL
+/// );
+/// ```
+#[derive(Debug, Clone)]
+pub struct RevokedUser {
+    /// User unique identifier from Google Firebase API
+    pub uid: String,
+    /// Tokens issued up to this moment are revoked
+    pub revoked_at: NaiveDateTime,
+}
+
+/// Refresh token model for the "refresh_tokens" table
+///
+/// Tokens issued by refreshing one another form a family sharing a single login
+///
+/// ```sql
+/// CREATE TABLE refresh_tokens (
+///     digest     BYTEA PRIMARY KEY,
+///     family     BYTEA NOT NULL,
+///     uid        TEXT NOT NULL,
+///     auth_time  TIMESTAMP NOT NULL,
+///     expires_at TIMESTAMP NOT NULL,
+///     used       BOOLEAN NOT NULL DEFAULT FALSE
+/// );
+/// CREATE INDEX ON refresh_tokens (family);
+/// ```
+#[derive(Debug, Clone)]
+pub struct RefreshToken {
+    /// SHA-256 of the raw refresh token
+    pub digest: Vec<u8>,
+    /// Random ID of the login session the token belongs to
+    pub family: Vec<u8>,
+    /// User unique identifier from Google Firebase API
+    pub uid: String,
+    /// Time the user has logged in with Firebase
+    pub auth_time: NaiveDateTime,
+    /// Refresh token expiration time
+    pub expires_at: NaiveDateTime,
+    /// Whether the token has already been exchanged for a new one
+    pub used: bool,
+}
+
+/// Rate limit counter model for the "rate_limits" table
+///
+/// ```sql
+/// CREATE TABLE rate_limits (
+///     key          TEXT NOT NULL,
+///     window_start BIGINT NOT NULL,
+///     hits         BIGINT NOT NULL,
+///     PRIMARY KEY (key, window_start)
+/// );
+/// ```
+#[derive(Debug, Clone)]
+pub struct RateLimitWindow {
+    /// Rate limited client within a route: `<route>:user:<uid>` or `<route>:ip:<address>`
+    pub key: String,
+    /// Unix time the fixed window starts at
+    pub window_start: i64,
+    /// Requests within the window
+    pub hits: i64,
+}
+
+impl<'a, C> From<&'a Token<C>> for User {
+    fn from(token: &'a Token<C>) -> Self {
+        User {
+            uid: token.uid.clone(),
+            username: None,
+            email: token.email.clone(),
+            auth_time: NaiveDateTime::from_timestamp(token.auth_time.unwrap_or(token.iat), 0),
+            auth_until: NaiveDateTime::from_timestamp(token.exp, 0),
+        }
     }
 }
 
@@ -46,6 +46,11 @@ impl AsyncPgPool {
         Ok(Self::new(connect(db_uri)?))
     }
 
+    /// Synchronous connection pool for long-living connections, i.e. LISTEN loops
+    pub fn sync_pool(&self) -> &SyncPgPool {
+        &self.conn_pool
+    }
+
     /// Execute a request with pooled connection from AsyncPgPool.
     /// Returns the future with a query result
     pub fn request<F, R>(&self, closure: F) -> CpuFuture<R::Item, Error>
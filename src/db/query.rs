@@ -1,10 +1,14 @@
 //! Convenience traits for easy querying
 
-use db::AsyncPgPool;
-use db::error::Error;
+use db::{AsyncPgPool, PgPooledConnection};
+use db::error::{Error, Result};
 use db::models::*;
 
 use futures_cpupool::CpuFuture;
+use postgres::types::ToSql;
+
+/// Channel revocations are announced with NOTIFY to all instances of the service
+pub const REVOCATIONS_CHANNEL: &str = "revocations";
 
 /// Convenient trait to simplify object insertion
 pub trait Insert {
@@ -31,3 +35,109 @@ impl Insert for User {
         })
     }
 }
+
+impl Insert for RevokedToken {
+    fn insert(self, pool: &AsyncPgPool) -> CpuFuture<u64, Error> {
+        use futures::future::result;
+
+        pool.request(move |conn| {
+            result(execute_and_notify(
+                &conn,
+                "INSERT INTO revoked_tokens VALUES($1, $2) ON CONFLICT DO NOTHING",
+                &[&self.digest, &self.expires_at],
+            ))
+        })
+    }
+}
+
+impl Insert for RevokedUser {
+    fn insert(self, pool: &AsyncPgPool) -> CpuFuture<u64, Error> {
+        use futures::future::result;
+
+        // Revoking a user again moves the revocation moment forward.
+        // Refresh tokens of the user go away with the revocation, ending all sessions
+        pool.request(move |conn| {
+            result(revoke_user(&conn, &self))
+        })
+    }
+}
+
+impl Insert for RefreshToken {
+    fn insert(self, pool: &AsyncPgPool) -> CpuFuture<u64, Error> {
+        use futures::future::result;
+
+        pool.request(move |conn| {
+            result(
+                conn.execute(
+                    "INSERT INTO refresh_tokens VALUES($1, $2, $3, $4, $5, $6)", &[
+                        &self.digest,
+                        &self.family,
+                        &self.uid,
+                        &self.auth_time,
+                        &self.expires_at,
+                        &self.used
+                ]).map_err(Error::from)
+            )
+        })
+    }
+}
+
+/// Count requests within the window, forgetting the windows before the previous one
+///
+/// Returns hits of the window and of the previous one
+pub fn count_hits(pool: &AsyncPgPool, window: RateLimitWindow, length: i64) -> CpuFuture<(i64, i64), Error> {
+    use futures::future::result;
+
+    pool.request(move |conn| result(count_window_hits(&conn, &window, length)))
+}
+
+fn count_window_hits(conn: &PgPooledConnection, window: &RateLimitWindow, length: i64) -> Result<(i64, i64)> {
+    let previous_start = window.window_start - length;
+    let transaction = conn.transaction()?;
+
+    let hits: i64 = {
+        let rows = transaction.query(
+            "INSERT INTO rate_limits VALUES($1, $2, $3)
+             ON CONFLICT (key, window_start) DO UPDATE SET hits = rate_limits.hits + $3
+             RETURNING hits",
+            &[&window.key, &window.window_start, &window.hits],
+        )?;
+        rows.get(0).get(0)
+    };
+    let previous: i64 = {
+        let rows = transaction.query(
+            "SELECT hits FROM rate_limits WHERE key = $1 AND window_start = $2",
+            &[&window.key, &previous_start],
+        )?;
+        rows.iter().next().map_or(0, |row| row.get(0))
+    };
+
+    transaction.execute(
+        "DELETE FROM rate_limits WHERE key = $1 AND window_start < $2",
+        &[&window.key, &previous_start],
+    )?;
+    transaction.commit()?;
+    Ok((hits, previous))
+}
+
+fn revoke_user(conn: &PgPooledConnection, user: &RevokedUser) -> Result<u64> {
+    let transaction = conn.transaction()?;
+    let changed = transaction.execute(
+        "INSERT INTO revoked_users VALUES($1, $2)
+         ON CONFLICT (uid) DO UPDATE SET revoked_at = $2",
+        &[&user.uid, &user.revoked_at],
+    )?;
+    transaction.execute("DELETE FROM refresh_tokens WHERE uid = $1", &[&user.uid])?;
+    transaction.execute("SELECT pg_notify($1, '')", &[&REVOCATIONS_CHANNEL])?;
+    transaction.commit()?;
+    Ok(changed)
+}
+
+/// Execute a revocation statement and notify the listeners in the same transaction
+fn execute_and_notify(conn: &PgPooledConnection, query: &str, params: &[&ToSql]) -> Result<u64> {
+    let transaction = conn.transaction()?;
+    let changed = transaction.execute(query, params)?;
+    transaction.execute("SELECT pg_notify($1, '')", &[&REVOCATIONS_CHANNEL])?;
+    transaction.commit()?;
+    Ok(changed)
+}
@@ -0,0 +1,52 @@
+//! Reading request bodies within a size limit
+
+use http::Context;
+use http::error::{Error, ErrorKind};
+
+use futures::{Future, Stream};
+use hyper::{Body, Request};
+
+/// Size limit of request bodies read without a `BodyLimit` middleware: 64 KiB
+pub const DEFAULT_MAX_BODY_SIZE: usize = 64 * 1024;
+
+/// Size limit of the request body, kept in the `Context` by a `BodyLimit` middleware
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct MaxBodySize(pub usize);
+
+pub type FutureBody = Box<Future<Item = Vec<u8>, Error = Error>>;
+
+/// Collect the body, failing as soon as it exceeds `limit` bytes
+pub fn collect(body: Body, limit: usize) -> FutureBody {
+    let collected = body
+        .map_err(|e| Error::from(ErrorKind::InvalidRequestBody(e.to_string())))
+        .fold(Vec::new(), move |mut collected, chunk| {
+            if collected.len() + chunk.len() > limit {
+                return Err(Error::from(ErrorKind::PayloadTooLarge(limit)));
+            }
+            collected.extend_from_slice(&chunk);
+            Ok(collected)
+        });
+    box collected
+}
+
+/// Collect the request body within the limit set for the request
+pub fn read(req: Request, ctx: &Context) -> FutureBody {
+    let limit = ctx.get::<MaxBodySize>().map_or(DEFAULT_MAX_BODY_SIZE, |max| max.0);
+    collect(req.body(), limit)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bodies_over_limit_are_rejected() {
+        let body = collect(Body::from("{\"refresh_token\":\"abc\"}"), 64).wait().unwrap();
+        assert_eq!(body, b"{\"refresh_token\":\"abc\"}");
+
+        match *collect(Body::from(vec![0u8; 65]), 64).wait().unwrap_err().kind() {
+            ErrorKind::PayloadTooLarge(64) => (),
+            ref e => panic!("unexpected error: {}", e),
+        }
+    }
+}
@@ -0,0 +1,85 @@
+//! Typed per-request context shared by the middleware of a chain
+
+use std::any::{Any, TypeId};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// Type-keyed map of values attached to a request on its way through `Chains`
+///
+/// Holds at most one value of each type: use newtypes to keep several of the same type.
+/// Clones share the same values. Unlike headers, nothing of it is ever sent on the wire
+#[derive(Clone, Default)]
+pub struct Context {
+    values: Rc<RefCell<HashMap<TypeId, Box<Any>>>>,
+}
+
+impl Context {
+    pub fn new() -> Self {
+        Context::default()
+    }
+
+    /// Attach a value, returning the previous value of the type
+    pub fn insert<T: 'static>(&self, value: T) -> Option<T> {
+        self.values
+            .borrow_mut()
+            .insert(TypeId::of::<T>(), box value)
+            .and_then(downcast)
+    }
+
+    /// Copy of the value of the type
+    pub fn get<T: Clone + 'static>(&self) -> Option<T> {
+        self.with(T::clone)
+    }
+
+    /// Call `f` with a reference to the value of the type
+    ///
+    /// The context must not be modified within `f`
+    pub fn with<T: 'static, R, F: FnOnce(&T) -> R>(&self, f: F) -> Option<R> {
+        self.values
+            .borrow()
+            .get(&TypeId::of::<T>())
+            .and_then(|value| value.downcast_ref())
+            .map(f)
+    }
+
+    pub fn contains<T: 'static>(&self) -> bool {
+        self.values.borrow().contains_key(&TypeId::of::<T>())
+    }
+
+    /// Detach the value of the type
+    pub fn remove<T: 'static>(&self) -> Option<T> {
+        self.values
+            .borrow_mut()
+            .remove(&TypeId::of::<T>())
+            .and_then(downcast)
+    }
+}
+
+fn downcast<T: 'static>(value: Box<Any>) -> Option<T> {
+    value.downcast().ok().map(|value| *value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Context;
+
+    #[derive(Debug, Clone, PartialEq)]
+    struct RequestStart(u64);
+
+    #[test]
+    fn values_are_keyed_by_type() {
+        let context = Context::new();
+        assert_eq!(context.insert(RequestStart(1)), None);
+        assert_eq!(context.insert(RequestStart(2)), Some(RequestStart(1)));
+        context.insert("12345".to_owned());
+
+        let shared = context.clone();
+        assert_eq!(shared.get::<RequestStart>(), Some(RequestStart(2)));
+        assert_eq!(shared.with(|uid: &String| uid.len()), Some(5));
+
+        assert_eq!(context.remove::<String>(), Some("12345".to_owned()));
+        assert!(!shared.contains::<String>());
+        assert!(shared.contains::<RequestStart>());
+    }
+}
@@ -0,0 +1,65 @@
+//! Deadlines of requests
+
+use futures::Future;
+use futures::future::Either;
+use tokio_core::reactor::{Handle, Timeout};
+
+use std::io;
+use std::time::{Duration, Instant};
+
+/// Moment the response to a request is due, kept in its `Context`
+///
+/// `Chains` race every middleware after the one that has set the deadline against it
+#[derive(Clone)]
+pub struct Deadline {
+    at: Instant,
+    handle: Handle,
+}
+
+impl Deadline {
+    /// Deadline in `timeout` from now, timed on the event loop of the handle
+    pub fn after(timeout: Duration, handle: &Handle) -> Self {
+        Deadline {
+            at: Instant::now() + timeout,
+            handle: handle.clone(),
+        }
+    }
+
+    pub fn at(&self) -> Instant {
+        self.at
+    }
+
+    /// Time left to respond, i.e. a timeout for a database query
+    pub fn remaining(&self) -> Duration {
+        let now = Instant::now();
+        if self.at > now { self.at - now } else { Duration::from_secs(0) }
+    }
+
+    /// Resolve with the result of the future, or with None if the deadline comes first
+    ///
+    /// A failure of the timer itself is ours and fails the race, it doesn't time out
+    pub fn race<F>(&self, future: F) -> Box<Future<Item = Option<F::Item>, Error = F::Error>>
+    where
+        F: Future + 'static,
+        F::Item: 'static,
+        F::Error: From<io::Error> + 'static,
+    {
+        let timer = match Timeout::new_at(self.at, &self.handle) {
+            Ok(timer) => timer,
+            Err(e) => {
+                error!("failed to set a request timer: {}", e);
+                return box future.map(Some);
+            },
+        };
+
+        box future.select2(timer).then(|result| match result {
+            Ok(Either::A((item, _))) => Ok(Some(item)),
+            Ok(Either::B(_)) => Ok(None),
+            Err(Either::A((e, _))) => Err(e),
+            Err(Either::B((e, _))) => {
+                error!("request timer failed: {}", e);
+                Err(F::Error::from(e))
+            },
+        })
+    }
+}
@@ -18,6 +18,11 @@ error_chain!{
             display("missing Authorization header")
         }
 
+        MalformedAuthHeader {
+            description("malformed Authorization header")
+            display("Authorization header is not a Bearer token")
+        }
+
         PathNotFound(method: Method, path: String) {
             description("path not found")
             display("path {} for method {} does not exist", path, method)
@@ -32,6 +37,51 @@ error_chain!{
             description("middleware chain haven't produced response")
             display("middleware chain haven't produced response")
         }
+
+        InvalidRequestBody(reason: String) {
+            description("invalid request body")
+            display("invalid request body: {}", reason)
+        }
+
+        LoginRequiresIdToken {
+            description("login requires a Firebase ID token")
+            display("login requires a Firebase ID token")
+        }
+
+        MiddlewareFailed {
+            description("internal server error")
+            display("internal server error")
+        }
+
+        MiddlewarePanicked {
+            description("internal server error")
+            display("internal server error")
+        }
+
+        PayloadTooLarge(limit: usize) {
+            description("request body is too large")
+            display("request body exceeds {} bytes", limit)
+        }
+
+        InvalidContentLength {
+            description("invalid Content-Length header")
+            display("invalid Content-Length header")
+        }
+
+        GatewayTimeout {
+            description("request timed out")
+            display("request timed out")
+        }
+
+        TooManyRequests {
+            description("too many requests")
+            display("too many requests")
+        }
+
+        InvalidSignedUserID {
+            description("invalid or expired SignedUserID header")
+            display("invalid or expired SignedUserID header")
+        }
     }
 }
 
@@ -43,11 +93,25 @@ impl From<Error> for ApiError {
                 ApiError::with_status(&e, StatusCode::InternalServerError)
             }
             ErrorKind::AuthHeaderMissing => ApiError::with_status(&e, StatusCode::Unauthorized),
+            ErrorKind::MalformedAuthHeader => ApiError::with_status(&e, StatusCode::Unauthorized),
             ErrorKind::PathNotFound(..) => ApiError::with_status(&e, StatusCode::NotFound),
             ErrorKind::MissingUserIDHeader => {
                 ApiError::with_status(&e, StatusCode::InternalServerError)
             }
             ErrorKind::UnfinishedChain => ApiError::with_status(&e, StatusCode::InternalServerError),
+            ErrorKind::InvalidRequestBody(..) => ApiError::with_status(&e, StatusCode::BadRequest),
+            ErrorKind::LoginRequiresIdToken => ApiError::with_status(&e, StatusCode::Unauthorized),
+            ErrorKind::MiddlewareFailed => ApiError::with_status(&e, StatusCode::InternalServerError),
+            ErrorKind::MiddlewarePanicked => {
+                ApiError::with_status(&e, StatusCode::InternalServerError)
+            }
+            ErrorKind::PayloadTooLarge(..) => ApiError::with_status(&e, StatusCode::PayloadTooLarge),
+            ErrorKind::InvalidContentLength => {
+                ApiError::with_status(&e, StatusCode::PayloadTooLarge)
+            }
+            ErrorKind::GatewayTimeout => ApiError::with_status(&e, StatusCode::GatewayTimeout),
+            ErrorKind::TooManyRequests => ApiError::with_status(&e, StatusCode::TooManyRequests),
+            ErrorKind::InvalidSignedUserID => ApiError::with_status(&e, StatusCode::Unauthorized),
             ErrorKind::Msg(..) => ApiError::with_status(&e, StatusCode::InternalServerError),
         }
     }
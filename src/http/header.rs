@@ -2,8 +2,11 @@
 
 use hyper;
 use hyper::header::Header;
+use hyper::header::Headers;
 use hyper::header::Raw;
 
+use base64;
+
 use std::fmt;
 use std::ops::Deref;
 use std::str;
@@ -35,9 +38,243 @@ impl Deref for UserID {
     }
 }
 
+/// Remove the identity headers only our middleware may set
+///
+/// Clients could otherwise impersonate any user on routes without an `Authenticator`
+pub fn strip_identity(headers: &mut Headers) {
+    headers.remove::<UserID>();
+}
+
+/// Header to pass ID of an authorized user between our services
+///
+/// Formatted as `<uid>.<issued at>.<base64url HMAC>`, see `SignedIdentity`
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct SignedUserID {
+    pub uid: String,
+    pub issued_at: i64,
+    pub signature: Vec<u8>,
+}
+
+impl SignedUserID {
+    /// Data covered by the signature
+    pub fn signed_data(&self) -> String {
+        format!("{}.{}", self.uid, self.issued_at)
+    }
+}
+
+impl Header for SignedUserID {
+    fn header_name() -> &'static str {
+        "SignedUserID"
+    }
+
+    fn parse_header(raw: &Raw) -> hyper::error::Result<Self> {
+        let raw_header = raw.one().ok_or(hyper::Error::Header)?;
+        let raw_header = str::from_utf8(&raw_header)?;
+
+        // User ID goes first and may contain dots itself
+        let mut parts = raw_header.rsplitn(3, '.');
+        let signature = parts.next().ok_or(hyper::Error::Header)?;
+        let issued_at = parts.next().ok_or(hyper::Error::Header)?;
+        let uid = parts.next().ok_or(hyper::Error::Header)?;
+
+        Ok(SignedUserID {
+            uid: uid.to_owned(),
+            issued_at: issued_at.parse().map_err(|_| hyper::Error::Header)?,
+            signature: base64::decode_config(signature, base64::URL_SAFE_NO_PAD)
+                .map_err(|_| hyper::Error::Header)?,
+        })
+    }
+
+    fn fmt_header(&self, f: &mut hyper::header::Formatter) -> fmt::Result {
+        let signature = base64::encode_config(&self.signature, base64::URL_SAFE_NO_PAD);
+        f.fmt_line(&format!("{}.{}", self.signed_data(), signature))
+    }
+}
+
+/// `X-Request-ID` header identifying a request in responses and logs
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct RequestID(pub String);
+
+impl Header for RequestID {
+    fn header_name() -> &'static str {
+        "X-Request-ID"
+    }
+
+    fn parse_header(raw: &Raw) -> hyper::error::Result<Self> {
+        let raw_header = raw.one().ok_or(hyper::Error::Header)?;
+        let raw_header = str::from_utf8(&raw_header)?;
+        Ok(RequestID(raw_header.to_owned()))
+    }
+
+    fn fmt_header(&self, f: &mut hyper::header::Formatter) -> fmt::Result {
+        f.fmt_line(&self.0)
+    }
+}
+
+impl Deref for RequestID {
+    type Target = str;
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+/// `X-API-Key` header some clients send their token in instead of `Authorization`
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ApiKey(pub String);
+
+impl Header for ApiKey {
+    fn header_name() -> &'static str {
+        "X-API-Key"
+    }
+
+    fn parse_header(raw: &Raw) -> hyper::error::Result<Self> {
+        let raw_header = raw.one().ok_or(hyper::Error::Header)?;
+        let raw_header = str::from_utf8(&raw_header)?;
+        Ok(ApiKey(raw_header.trim().to_owned()))
+    }
+
+    fn fmt_header(&self, f: &mut hyper::header::Formatter) -> fmt::Result {
+        f.fmt_line(&self.0)
+    }
+}
+
+impl Deref for ApiKey {
+    type Target = str;
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+/// Error codes of the Bearer authentication scheme (RFC 6750, section 3.1)
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum BearerError {
+    /// The request is malformed: i.e. it's missing a parameter or has several credentials
+    InvalidRequest,
+    /// The token is expired, revoked, malformed, or invalid for other reasons
+    InvalidToken,
+    /// The request requires higher privileges than the token provides
+    InsufficientScope,
+}
+
+impl BearerError {
+    /// Error code as in the `error` attribute
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            BearerError::InvalidRequest => "invalid_request",
+            BearerError::InvalidToken => "invalid_token",
+            BearerError::InsufficientScope => "insufficient_scope",
+        }
+    }
+}
+
+/// `WWW-Authenticate` header with a Bearer challenge (RFC 6750)
+///
+/// A challenge without an error tells the client to authenticate,
+/// one with an error tells why the presented token has been rejected
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct WwwAuthenticate {
+    /// Protection space of the challenge
+    pub realm: String,
+    /// Reason the token has been rejected
+    pub error: Option<BearerError>,
+    /// Human-readable explanation of the error
+    pub error_description: Option<String>,
+}
+
+impl WwwAuthenticate {
+    /// Challenge a client that hasn't presented any credentials
+    pub fn bearer<R: Into<String>>(realm: R) -> Self {
+        WwwAuthenticate {
+            realm: realm.into(),
+            error: None,
+            error_description: None,
+        }
+    }
+
+    /// Challenge a client whose credentials have been rejected
+    pub fn error<D: fmt::Display>(mut self, error: BearerError, description: &D) -> Self {
+        self.error = Some(error);
+        self.error_description = Some(description.to_string());
+        self
+    }
+}
+
+impl Header for WwwAuthenticate {
+    fn header_name() -> &'static str {
+        "WWW-Authenticate"
+    }
+
+    fn parse_header(raw: &Raw) -> hyper::error::Result<Self> {
+        let raw_header = raw.one().ok_or(hyper::Error::Header)?;
+        let raw_header = str::from_utf8(&raw_header)?;
+        if !raw_header.starts_with("Bearer ") {
+            return Err(hyper::Error::Header);
+        }
+
+        let mut header = WwwAuthenticate::bearer("");
+        for param in split_params(&raw_header["Bearer ".len()..]) {
+            let mut pair = param.splitn(2, '=');
+            let name = pair.next().unwrap_or("").trim();
+            let value = pair.next().ok_or(hyper::Error::Header)?.trim().trim_matches('"');
+            match name {
+                "realm" => header.realm = value.to_owned(),
+                "error" => {
+                    header.error = Some(match value {
+                        "invalid_request" => BearerError::InvalidRequest,
+                        "invalid_token" => BearerError::InvalidToken,
+                        "insufficient_scope" => BearerError::InsufficientScope,
+                        _ => return Err(hyper::Error::Header),
+                    })
+                }
+                "error_description" => header.error_description = Some(value.to_owned()),
+                _ => (),
+            }
+        }
+        Ok(header)
+    }
+
+    fn fmt_header(&self, f: &mut hyper::header::Formatter) -> fmt::Result {
+        let mut challenge = format!("Bearer realm=\"{}\"", quotable(&self.realm));
+        if let Some(error) = self.error {
+            challenge.push_str(&format!(", error=\"{}\"", error.as_str()));
+        }
+        if let Some(ref description) = self.error_description {
+            challenge.push_str(&format!(", error_description=\"{}\"", quotable(description)));
+        }
+        f.fmt_line(&challenge)
+    }
+}
+
+/// Drop characters RFC 6750 doesn't allow in quoted attribute values
+fn quotable(value: &str) -> String {
+    value
+        .chars()
+        .filter(|&c| c >= ' ' && c <= '~' && c != '"' && c != '\\')
+        .collect()
+}
+
+/// Split challenge parameters by commas outside of quotes
+fn split_params(params: &str) -> Vec<&str> {
+    let mut split = Vec::new();
+    let mut quoted = false;
+    let mut start = 0;
+    for (i, c) in params.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            ',' if !quoted => {
+                split.push(&params[start..i]);
+                start = i + 1;
+            }
+            _ => (),
+        }
+    }
+    split.push(&params[start..]);
+    split
+}
+
 #[cfg(test)]
 mod tests {
-    use super::UserID;
+    use super::{strip_identity, BearerError, SignedUserID, UserID, WwwAuthenticate};
     use hyper::header::Header;
     use hyper::header::Headers;
     use hyper::header::Raw;
@@ -58,4 +295,46 @@ mod tests {
         let header = UserID::parse_header(&raw).unwrap();
         assert_eq!(user_id, &header.0);
     }
+
+    #[test]
+    fn signed_user_id() {
+        let signed = SignedUserID {
+            uid: "user.12345".to_owned(),
+            issued_at: 1_500_000_000,
+            signature: vec![1, 2, 3, 250],
+        };
+        let mut headers = Headers::new();
+        headers.set(signed.clone());
+        assert_eq!(headers.get_raw("SignedUserID").unwrap(), "user.12345.1500000000.AQID-g");
+        assert_eq!(*headers.get::<SignedUserID>().unwrap(), signed);
+    }
+
+    #[test]
+    fn identity_is_stripped() {
+        let mut headers = Headers::new();
+        headers.set(UserID("someone-else".to_owned()));
+        strip_identity(&mut headers);
+        assert_eq!(headers.len(), 0);
+    }
+
+    #[test]
+    fn www_authenticate() {
+        let challenge = WwwAuthenticate::bearer("circles")
+            .error(BearerError::InvalidToken, &"token has expired, \"really\"");
+        let mut headers = Headers::new();
+        headers.set(challenge.clone());
+        assert_eq!(
+            headers.get_raw("WWW-Authenticate").unwrap(),
+            r#"Bearer realm="circles", error="invalid_token", error_description="token has expired, really""#
+        );
+
+        let parsed = headers.get::<WwwAuthenticate>().unwrap();
+        assert_eq!(parsed.realm, "circles");
+        assert_eq!(parsed.error, Some(BearerError::InvalidToken));
+        assert_eq!(parsed.error_description, Some("token has expired, really".to_owned()));
+
+        let mut headers = Headers::new();
+        headers.set(WwwAuthenticate::bearer("circles"));
+        assert_eq!(headers.get_raw("WWW-Authenticate").unwrap(), r#"Bearer realm="circles""#);
+    }
 }
@@ -1,20 +1,28 @@
+use std::any::Any;
 use std::rc::Rc;
 use std::io;
+use std::panic::AssertUnwindSafe;
 
 use hyper;
 use hyper::server::{Request, Response};
 use hyper::server::{Service, NewService};
 
 use http::ApiError;
+use http::{Context, Deadline};
 use http::error::ErrorKind;
+use http::header;
+use http::header::RequestID;
+use http::request_id;
 use http::ServerResponse;
 
-use futures::Future;
+use futures::{Future, Stream};
 use futures::future::{
     ok,
+    lazy,
     loop_fn,
     Loop,
 };
+use futures::stream;
 
 type ChainsInner = Vec<Box<CloneableMiddleware>>;
 type RcChainsInner = Rc<Vec<Box<CloneableMiddleware>>>;
@@ -69,42 +77,128 @@ impl Service for Chains {
     type Error = hyper::Error;
     type Future = Box<Future<Item = Self::Response, Error = Self::Error>>;
 
-    fn call(&self, req: Request) -> Self::Future {
-        trace!("accepted {} request for {}", req.method(), req.uri());
+    fn call(&self, mut req: Request) -> Self::Future {
+        let id = accept(&mut req);
+
+        // Method and path only: the query may carry credentials
+        let target = Rc::new(format!("{} {}", req.method(), req.path()));
+        let after_target = target.clone();
 
         let chains = self.clone();
+        let after_chains = self.clone();
 
         type LoopResult<L, B> = Box<Future<Item = Loop<L, B>, Error = hyper::Error>>;
 
+        // Context lives as long as the request goes through the chain
+        let context = Context::new();
+        let after_context = context.clone();
+        context.insert(RequestID(id.clone()));
+
+        // Break with the result and the number of middleware that handled the request
         let future = loop_fn((req, 0), move |(req, idx)| -> LoopResult<_, _> {
-            if let Some(chain) = chains.get(idx) {
-                box chain.handle(req).and_then(move |ts| {
-                    match ts {
-                        Transition::Request(req) => {
-                            ok(Loop::Continue((req, idx + 1)))
-                        },
-                        Transition::Response(resp) => {
-                            ok(Loop::Break(Ok(resp)))
-                        }
-                    }
-                })
-            } else {
-                box ok(Loop::Break(Err(ErrorKind::UnfinishedChain)))
-            }
+            let chain = match chains.get(idx) {
+                Some(chain) => chain,
+                None => return box ok(Loop::Break((Err(ErrorKind::UnfinishedChain), idx))),
+            };
+
+            // Lazy, so that a middleware panicking right away is caught too
+            let ctx = context.clone();
+            let handled = lazy(move || chain.handle(req, ctx));
+            let handled: Box<Future<Item = _, Error = _>> = match context.get::<Deadline>() {
+                Some(deadline) => deadline.race(handled),
+                None => box handled.map(Some),
+            };
+
+            // A failed middleware is gone, the ones before it still see the error response
+            let target = target.clone();
+            let handled = AssertUnwindSafe(handled).catch_unwind();
+            box handled.then(move |result| -> Result<_, hyper::Error> {
+                let error = match result {
+                    Ok(Ok(Some(Transition::Request(req)))) => {
+                        return Ok(Loop::Continue((req, idx + 1)))
+                    },
+                    Ok(Ok(Some(Transition::Response(resp)))) => {
+                        return Ok(Loop::Break((Ok(resp), idx + 1)))
+                    },
+                    Ok(Ok(None)) => ErrorKind::GatewayTimeout,
+                    Ok(Err(e)) => {
+                        error!("{} failed: {}", target, e);
+                        ErrorKind::MiddlewareFailed
+                    },
+                    Err(panic) => {
+                        error!("{} panicked: {}", target, panic_message(&*panic));
+                        ErrorKind::MiddlewarePanicked
+                    },
+                };
+                Ok(Loop::Break((Err(error), idx)))
+            })
         });
 
-        box future.map(|result| {
-            match result {
+        let future = future.and_then(move |(result, handled)| {
+            let response = match result {
                 Ok(resp) => resp,
                 Err(err) => ServerResponse::from(err).into()
-            }
-        })
+            };
+
+            // The response goes back through the same middleware in reverse order
+            let unwind = stream::iter_ok::<_, hyper::Error>((0..handled).rev());
+            unwind.fold(response, move |response, idx| -> FutureResponse {
+                match after_chains.get(idx) {
+                    Some(chain) => chain.after(response, after_context.clone()),
+                    None => box ok(response),
+                }
+            })
+        });
+
+        // Nor may a failing after hook take down the connection
+        let future = AssertUnwindSafe(future).catch_unwind();
+        let future = future.then(move |result| -> Result<_, hyper::Error> {
+            let error = match result {
+                Ok(Ok(response)) => return Ok(response),
+                Ok(Err(e)) => {
+                    error!("{} failed: {}", after_target, e);
+                    ErrorKind::MiddlewareFailed
+                },
+                Err(panic) => {
+                    error!("{} panicked: {}", after_target, panic_message(&*panic));
+                    ErrorKind::MiddlewarePanicked
+                },
+            };
+            Ok(ServerResponse::from(error).into())
+        });
+
+        // Echo the ID and tag everything logged meanwhile with it
+        let header = RequestID(id.clone());
+        box request_id::scope(id, future.map(move |response: Response| {
+            response.with_header(header)
+        }))
+    }
+}
+
+/// Prepare a request coming from a client to be handled, returning its request ID
+///
+/// Everything answering requests directly calls it first, `Chains` and the router alike
+pub fn accept(req: &mut Request) -> String {
+    let id = request_id::assign(req.headers_mut());
+    trace!("accepted {} request for {} as {}", req.method(), req.uri(), id);
+
+    // Identity comes from our middleware only, never from clients
+    header::strip_identity(req.headers_mut());
+    id
+}
+
+/// Message a panic has been started with, if it is a string
+fn panic_message(panic: &(Any + Send)) -> &str {
+    match panic.downcast_ref::<&'static str>() {
+        Some(message) => message,
+        None => panic.downcast_ref::<String>().map_or("unknown cause", String::as_str),
     }
 }
 
 
 pub type TransitionResult = Result<Transition, hyper::Error>;
 pub type FutureTransition = Box<Future<Item = Transition, Error = hyper::Error>>;
+pub type FutureResponse = Box<Future<Item = Response, Error = hyper::Error>>;
 
 pub enum Transition {
     Request(Request),
@@ -130,7 +224,18 @@ impl Transition {
 }
 
 pub trait Middleware {
-    fn handle(self: Box<Self>, req: Request) -> FutureTransition;
+    /// Handle the request, either passing it further or responding
+    ///
+    /// Values put into the context are seen by the later middleware of the chain
+    fn handle(self: Box<Self>, req: Request, ctx: Context) -> FutureTransition;
+
+    /// Process the response on its way back
+    ///
+    /// Called in reverse order for every middleware that has handled the request,
+    /// including the one that responded, with the same context
+    fn after(self: Box<Self>, response: Response, _ctx: Context) -> FutureResponse {
+        box ok(response)
+    }
 }
 
 pub trait CloneableMiddleware: Middleware {
@@ -142,3 +247,90 @@ impl<T: Middleware + Clone + 'static> CloneableMiddleware for T {
         box Clone::clone(self)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::{Method, StatusCode};
+    use hyper::header::Server;
+    use tokio_core::reactor::Core;
+
+    /// Records the order it sees the request and the response in
+    #[derive(Clone)]
+    struct Layer(&'static str);
+
+    #[derive(Clone, Default)]
+    struct Trace(Vec<String>);
+
+    fn trace(ctx: &Context, event: String) {
+        let mut trace = ctx.remove::<Trace>().unwrap_or_default();
+        trace.0.push(event);
+        ctx.insert(trace);
+    }
+
+    impl Middleware for Layer {
+        fn handle(self: Box<Self>, req: Request, ctx: Context) -> FutureTransition {
+            trace(&ctx, format!("handle {}", self.0));
+            if self.0 == "inner" {
+                return box ok(Transition::Response(Response::new()));
+            }
+            box ok(Transition::Request(req))
+        }
+
+        fn after(self: Box<Self>, response: Response, ctx: Context) -> FutureResponse {
+            trace(&ctx, format!("after {}", self.0));
+            let trace = ctx.get::<Trace>().unwrap().0.join(", ");
+            box ok(response.with_header(Server::new(trace)))
+        }
+    }
+
+    #[derive(Clone)]
+    struct Unimplemented;
+
+    impl Middleware for Unimplemented {
+        fn handle(self: Box<Self>, _req: Request, _ctx: Context) -> FutureTransition {
+            unimplemented!()
+        }
+    }
+
+    #[test]
+    fn panics_are_internal_errors() {
+        let chains = Chains::builder()
+            .chain(box Layer("outer"))
+            .chain(box Unimplemented)
+            .build();
+
+        let mut core = Core::new().unwrap();
+        let request = Request::new(Method::Get, "/".parse().unwrap());
+        let response = core.run(chains.call(request)).unwrap();
+        assert_eq!(response.status(), StatusCode::InternalServerError);
+
+        // The middleware that passed the request on still sees the error
+        assert_eq!(
+            response.headers().get::<Server>().unwrap().to_string(),
+            "handle outer, after outer"
+        );
+
+        // The client can tell the server which request failed
+        let id = response.headers().get::<RequestID>().unwrap().0.clone();
+        let body = core.run(response.body().concat2()).unwrap();
+        assert!(String::from_utf8_lossy(&body).contains(&id));
+    }
+
+    #[test]
+    fn responses_go_back_through_handled_middleware() {
+        let chains = Chains::builder()
+            .chain(box Layer("outer"))
+            .chain(box Layer("inner"))
+            .chain(box Layer("unreached"))
+            .build();
+
+        let mut core = Core::new().unwrap();
+        let request = Request::new(Method::Get, "/".parse().unwrap());
+        let response = core.run(chains.call(request)).unwrap();
+        assert_eq!(
+            response.headers().get::<Server>().unwrap().to_string(),
+            "handle outer, handle inner, after inner, after outer"
+        );
+    }
+}
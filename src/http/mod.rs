@@ -1,10 +1,16 @@
 #[macro_use]
 pub mod service;
+pub mod body;
+pub mod context;
+pub mod deadline;
 pub mod error;
 pub mod header;
+pub mod request_id;
 pub mod response;
 pub mod middleware;
 
+pub use self::context::Context;
+pub use self::deadline::Deadline;
 pub use self::response::ApiError;
 pub use self::response::ServerResponse;
 
@@ -0,0 +1,109 @@
+//! Request IDs correlating the responses and logs of a request
+
+use futures::{Future, Poll};
+use hyper::Headers;
+use openssl::rand::rand_bytes;
+
+use http::header::RequestID;
+
+use std::cell::RefCell;
+use std::mem;
+
+/// Longest request ID accepted from clients
+const MAX_LENGTH: usize = 128;
+
+thread_local! {
+    static CURRENT: RefCell<Option<String>> = RefCell::new(None);
+}
+
+/// ID of the request being handled on this thread, if any
+///
+/// Only set while a future wrapped with `scope` is polled,
+/// so tasks moved to a CpuPool don't see it
+pub fn current() -> Option<String> {
+    CURRENT.with(|current| current.borrow().clone())
+}
+
+/// Generate a new random request ID
+pub fn generate() -> String {
+    let mut bytes = [0; 16];
+    rand_bytes(&mut bytes).expect("failed to generate a request id");
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Whether a client supplied request ID is safe to use and log
+pub fn is_valid(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_LENGTH && id.bytes().all(|byte| byte.is_ascii_graphic())
+}
+
+/// ID of a request: the one a client or a proxy has given it if it's a sane one, or a new one
+///
+/// The ID is set on the headers, so that it's kept by everything handling the request later
+pub fn assign(headers: &mut Headers) -> String {
+    let id = match headers.get::<RequestID>() {
+        Some(id) if is_valid(id) => id.0.clone(),
+        _ => generate(),
+    };
+    headers.set(RequestID(id.clone()));
+    id
+}
+
+/// Make the request ID `current` while the future is polled
+pub fn scope<F: Future>(id: String, future: F) -> Scoped<F> {
+    Scoped { id, future }
+}
+
+/// Future returned by `scope`
+pub struct Scoped<F> {
+    id: String,
+    future: F,
+}
+
+impl<F: Future> Future for Scoped<F> {
+    type Item = F::Item;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let id = Some(self.id.clone());
+        let previous = CURRENT.with(|current| mem::replace(&mut *current.borrow_mut(), id));
+        let _restore = Restore(previous);
+        self.future.poll()
+    }
+}
+
+/// Restores the previous request ID, even if polling panics
+struct Restore(Option<String>);
+
+impl Drop for Restore {
+    fn drop(&mut self) {
+        let previous = self.0.take();
+        CURRENT.with(|current| *current.borrow_mut() = previous);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future;
+
+    #[test]
+    fn current_within_scope() {
+        let id = generate();
+        assert_eq!(id.len(), 32);
+        assert!(is_valid(&id));
+        assert!(!is_valid("forged\nlog line"));
+
+        let scoped = scope(id.clone(), future::lazy(|| future::ok::<_, ()>(current())));
+        assert_eq!(scoped.wait().unwrap(), Some(id));
+        assert_eq!(current(), None);
+    }
+
+    #[test]
+    fn assigned_once() {
+        let mut headers = Headers::new();
+        headers.set(RequestID("forged\nlog line".to_owned()));
+        let id = assign(&mut headers);
+        assert!(is_valid(&id));
+        assert_eq!(assign(&mut headers), id);
+    }
+}
@@ -1,8 +1,11 @@
 //! Server Response with an error message
 
 
+use http::request_id;
+
 use hyper::Response;
 use hyper::StatusCode;
+use hyper::header::{ContentLength, Header, Headers};
 
 use json;
 use serde::Serialize;
@@ -64,13 +67,18 @@ where
     D: Serialize,
 {
     fn into(self) -> Response {
+        let mut response = Response::default();
         let (status, body) = match self {
             ServerResponse::Data(data) => (StatusCode::Ok, json::to_string(&data).unwrap()),
-            ServerResponse::Error(error) => (error.status_code, json::to_string(&error).unwrap()),
+            ServerResponse::Error(mut error) => {
+                error.request_id = request_id::current();
+                response.headers_mut().extend(error.headers.iter());
+                (error.status_code, json::to_string(&error).unwrap())
+            },
         };
 
-        let mut response = Response::default();
         response.set_status(status);
+        response.headers_mut().set(ContentLength(body.len() as u64));
         response.set_body(body);
         response
     }
@@ -139,8 +147,12 @@ where
 pub struct ApiError {
     status: String,
     message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
     #[serde(skip_serializing)]
     status_code: StatusCode,
+    #[serde(skip_serializing)]
+    headers: Headers,
 }
 
 impl ApiError {
@@ -152,9 +164,27 @@ impl ApiError {
         ApiError {
             status: format!("{}", status),
             message: format!("{}", d),
+            request_id: None,
             status_code: status,
+            headers: Headers::new(),
         }
     }
+
+    /// Add a header to the response the error is returned with
+    pub fn with_header<H: Header>(mut self, header: H) -> Self {
+        self.headers.set(header);
+        self
+    }
+
+    /// HTTP StatusCode of the error
+    pub fn status_code(&self) -> StatusCode {
+        self.status_code
+    }
+
+    /// Headers of the response the error is returned with
+    pub fn headers(&self) -> &Headers {
+        &self.headers
+    }
 }
 
 impl Display for ApiError {
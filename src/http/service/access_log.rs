@@ -0,0 +1,222 @@
+//! Access log of completed requests
+
+use http::Context;
+use http::header::{RequestID, UserID};
+
+use hyper::{HttpVersion, Method, Request, Response};
+use hyper::header::{ContentLength, Referer, UserAgent};
+
+use chrono::{DateTime, Utc};
+use futures::future::ok;
+use futures::prelude::*;
+use json;
+
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use http::middleware::{
+    FutureResponse,
+    Middleware,
+    Transition,
+    TransitionResult
+};
+
+/// Log target of access log lines, to route them apart from the application logs
+pub const ACCESS_LOG_TARGET: &str = "access";
+
+/// Format of access log lines
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum LogFormat {
+    /// Common Log Format
+    Common,
+    /// Combined Log Format followed by the request ID and latency in milliseconds
+    Combined,
+    /// JSON object per line
+    Json,
+}
+
+/// Middleware logging one line per completed request at the `access` target
+///
+/// Chain it first, before the `RequestTimeout` and `Cors`,
+/// to see the final response of every other middleware.
+/// The user is known if an `Authenticator` has put the `UserID` into the context
+#[derive(Debug, Copy, Clone)]
+pub struct AccessLog {
+    format: LogFormat,
+}
+
+impl AccessLog {
+    pub fn new(format: LogFormat) -> Self {
+        AccessLog { format }
+    }
+}
+
+impl Default for AccessLog {
+    fn default() -> Self {
+        AccessLog::new(LogFormat::Combined)
+    }
+}
+
+/// Request data kept in the context until the response is ready
+#[derive(Debug, Clone)]
+struct Accepted {
+    start: Instant,
+    time: DateTime<Utc>,
+    method: Method,
+    path: String,
+    version: HttpVersion,
+    client: Option<SocketAddr>,
+    referer: Option<String>,
+    user_agent: Option<String>,
+}
+
+/// Line of the access log
+#[derive(Debug, Serialize)]
+struct AccessRecord {
+    /// Serialized in RFC 3339
+    time: DateTime<Utc>,
+    method: String,
+    path: String,
+    version: String,
+    status: u16,
+    size: Option<u64>,
+    latency_ms: f64,
+    user_id: Option<String>,
+    request_id: Option<String>,
+    client: Option<String>,
+    referer: Option<String>,
+    user_agent: Option<String>,
+}
+
+impl Middleware for AccessLog {
+    #[async(boxed)]
+    fn handle(self: Box<Self>, req: Request, ctx: Context) -> TransitionResult {
+        #[allow(deprecated)]
+        let client = req.remote_addr();
+        ctx.insert(Accepted {
+            start: Instant::now(),
+            time: Utc::now(),
+            method: req.method().clone(),
+            // Query strings may carry credentials, see `TokenSource::Query`
+            path: req.path().to_owned(),
+            version: req.version(),
+            client,
+            referer: req.headers().get::<Referer>().map(|referer| referer.to_string()),
+            user_agent: req.headers().get::<UserAgent>().map(|agent| agent.to_string()),
+        });
+
+        Ok(Transition::Request(req))
+    }
+
+    fn after(self: Box<Self>, response: Response, ctx: Context) -> FutureResponse {
+        if let Some(accepted) = ctx.remove::<Accepted>() {
+            let record = AccessRecord {
+                time: accepted.time,
+                method: accepted.method.to_string(),
+                path: accepted.path,
+                version: accepted.version.to_string(),
+                status: response.status().as_u16(),
+                size: response.headers().get::<ContentLength>().map(|length| length.0),
+                latency_ms: millis(accepted.start.elapsed()),
+                user_id: ctx.get::<UserID>().map(|uid| uid.0),
+                request_id: ctx.get::<RequestID>().map(|id| id.0),
+                client: accepted.client.map(|addr| addr.ip().to_string()),
+                referer: accepted.referer,
+                user_agent: accepted.user_agent,
+            };
+            info!(target: ACCESS_LOG_TARGET, "{}", record.format(self.format));
+        }
+
+        box ok(response)
+    }
+}
+
+impl AccessRecord {
+    fn format(&self, format: LogFormat) -> String {
+        let request = format!("{} {} {}", self.method, self.path, self.version);
+        let common = format!(
+            r#"{} - {} [{}] "{}" {} {}"#,
+            dash(&self.client),
+            dash(&self.user_id),
+            self.time.format("%d/%b/%Y:%H:%M:%S %z"),
+            escape(&request),
+            self.status,
+            self.size.map_or("-".to_owned(), |size| size.to_string())
+        );
+
+        match format {
+            LogFormat::Common => common,
+            LogFormat::Combined => format!(
+                r#"{} "{}" "{}" {} {:.3}"#,
+                common,
+                escape(dash(&self.referer)),
+                escape(dash(&self.user_agent)),
+                dash(&self.request_id),
+                self.latency_ms
+            ),
+            LogFormat::Json => json::to_string(self).unwrap(),
+        }
+    }
+}
+
+fn dash(value: &Option<String>) -> &str {
+    value.as_ref().map_or("-", String::as_str)
+}
+
+/// Escape a value quoted in a log line, so clients can't end the quotes
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs() as f64 * 1000.0 + f64::from(duration.subsec_nanos()) / 1_000_000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use hyper::Method;
+    use tokio_core::reactor::Core;
+
+    #[test]
+    fn query_is_not_logged() {
+        let uri = "/events?access_token=secret".parse().unwrap();
+        let ctx = Context::new();
+        let mut core = Core::new().unwrap();
+        core.run((box AccessLog::default()).handle(Request::new(Method::Get, uri), ctx.clone()))
+            .unwrap();
+
+        assert_eq!(ctx.get::<Accepted>().unwrap().path, "/events");
+    }
+
+    #[test]
+    fn log_formats() {
+        let record = AccessRecord {
+            time: Utc.ymd(2017, 10, 10).and_hms(13, 55, 36),
+            method: "GET".to_owned(),
+            path: "/restricted?page=2".to_owned(),
+            version: "HTTP/1.1".to_owned(),
+            status: 200,
+            size: Some(15),
+            latency_ms: 1.5,
+            user_id: Some("12345".to_owned()),
+            request_id: Some("abc".to_owned()),
+            client: Some("127.0.0.1".to_owned()),
+            referer: None,
+            user_agent: Some(r#"curl/7.54 "quoted\""#.to_owned()),
+        };
+
+        let common = r#"127.0.0.1 - 12345 [10/Oct/2017:13:55:36 +0000] "GET /restricted?page=2 HTTP/1.1" 200 15"#;
+        assert_eq!(record.format(LogFormat::Common), common);
+        assert_eq!(
+            record.format(LogFormat::Combined),
+            format!(r#"{} "-" "curl/7.54 \"quoted\\\"" abc 1.500"#, common)
+        );
+
+        let logged: json::Value = json::from_str(&record.format(LogFormat::Json)).unwrap();
+        assert_eq!(logged["time"], "2017-10-10T13:55:36Z");
+        assert_eq!(logged["user_id"], "12345");
+        assert_eq!(logged["status"], 200);
+    }
+}
@@ -1,10 +1,11 @@
 //! Request authentication proxy middleware
 
-use token::AsyncTokenVerifier;
-use http::ApiError;
+use token;
+use token::{Authority, NoClaims};
+use http::{ApiError, Context};
 
 use http::error::ErrorKind;
-use http::header::UserID;
+use http::header::{ApiKey, BearerError, UserID, WwwAuthenticate};
 
 use http::middleware::{
     Middleware,
@@ -12,67 +13,323 @@ use http::middleware::{
     TransitionResult,
 };
 
-use hyper::Request;
-use hyper::header::{Authorization, Bearer};
+use hyper::{Request, StatusCode};
+use hyper::header::{Authorization, Bearer, Cookie};
 
 use futures::prelude::*;
+use serde::de::DeserializeOwned;
+
+use std::fmt;
+use std::marker::PhantomData;
 use std::rc::Rc;
 
+/// Part of a request a token may be passed in
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum TokenSource {
+    /// `Authorization: Bearer` header
+    Bearer,
+    /// Cookie with the given name, for browsers
+    Cookie(String),
+    /// Percent-encoded query parameter with the given name,
+    /// for WebSocket and EventSource clients that can't set headers
+    Query(String),
+    /// `X-API-Key` header
+    ApiKey,
+}
+
+impl TokenSource {
+    fn extract(&self, req: &Request) -> Option<String> {
+        let headers = req.headers();
+        match *self {
+            TokenSource::Bearer => headers
+                .get::<Authorization<Bearer>>()
+                .map(|bearer| bearer.token.clone()),
+            TokenSource::Cookie(ref name) => headers
+                .get::<Cookie>()
+                .and_then(|cookie| cookie.get(name))
+                .map(str::to_owned),
+            TokenSource::Query(ref name) => req.query().and_then(|query| query_param(query, name)),
+            TokenSource::ApiKey => headers.get::<ApiKey>().map(|key| key.0.clone()),
+        }
+    }
+}
+
+impl fmt::Display for TokenSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TokenSource::Bearer => write!(f, "bearer header"),
+            TokenSource::Cookie(ref name) => write!(f, "cookie {}", name),
+            TokenSource::Query(ref name) => write!(f, "query parameter {}", name),
+            TokenSource::ApiKey => write!(f, "X-API-Key header"),
+        }
+    }
+}
+
 /// Authenticator Service factory with "persistent" state
 ///
+/// Tokens are verified by an `Authority`: an `AsyncTokenVerifier` accepting
+/// Firebase ID tokens, session tokens and service tokens signed with a shared secret,
+/// or an `AsyncIntrospector` for opaque tokens.
+/// Authorized requests are passed further with the verified `Token<C>`
+/// and the `UserID` in the `Context`: the token has the scheme,
+/// the service ID of service tokens and the custom claims of type `C`.
+///
+/// Rejected requests get a `WWW-Authenticate` Bearer challenge (RFC 6750):
+/// without an error code if there were no credentials,
+/// with `invalid_token` if the token is expired, revoked or otherwise invalid.
+///
+/// An optional Authenticator passes requests without credentials further anonymously,
+/// still rejecting invalid ones, so a request without the `UserID` in the `Context`
+/// is an anonymous one. Identity headers sent by clients are removed by `Chains`.
+///
+/// Tokens are taken from the first of the configured `TokenSource`s that has one,
+/// by default only from the `Authorization: Bearer` header.
+///
 /// For usage example please refer to one of already implemented microservices
 #[derive(Clone)]
-pub struct Authenticator {
-    auth: Rc<AsyncTokenVerifier>,
+pub struct Authenticator<C = NoClaims> {
+    auth: Rc<Authority>,
+    realm: Rc<String>,
+    sources: Rc<Vec<TokenSource>>,
+    optional: bool,
+    claims: PhantomData<fn() -> C>,
 }
 
-impl Authenticator {
+impl<C> Authenticator<C> {
     /// Create a new AuthenticatorService factory with persistent state
-    pub fn new() -> Self {
+    pub fn new<A: Authority + 'static>(authority: A) -> Self {
         info!("Created Authenticator (Service Factory)");
         Authenticator {
-            auth: Rc::new(AsyncTokenVerifier::new()),
+            auth: Rc::new(authority),
+            realm: Rc::new(env!("CARGO_PKG_NAME").to_owned()),
+            sources: Rc::new(vec![TokenSource::Bearer]),
+            optional: false,
+            claims: PhantomData,
         }
     }
 
-    fn extract_token(req: &Request) -> Result<&str, ApiError> {
-        let headers = req.headers();
-        let bearer: &Authorization<Bearer> = headers.get().ok_or(ApiError::from(
-            ErrorKind::AuthHeaderMissing,
-        ))?;
+    /// Set the realm of `WWW-Authenticate` challenges
+    pub fn realm<R: Into<String>>(mut self, realm: R) -> Self {
+        self.realm = Rc::new(realm.into());
+        self
+    }
+
+    /// Set the token sources to try, in order
+    pub fn sources(mut self, sources: Vec<TokenSource>) -> Self {
+        self.sources = Rc::new(sources);
+        self
+    }
+
+    /// Pass requests without credentials further anonymously instead of rejecting them
+    pub fn optional(mut self) -> Self {
+        self.optional = true;
+        self
+    }
+
+    /// Share the token authority with an Authenticator passing custom claims of another type
+    pub fn with_claims<D>(&self) -> Authenticator<D> {
+        Authenticator {
+            auth: self.auth.clone(),
+            realm: self.realm.clone(),
+            sources: self.sources.clone(),
+            optional: self.optional,
+            claims: PhantomData,
+        }
+    }
+
+    /// Token from the first source that has one, `None` if the request has no credentials
+    fn extract_token(&self, req: &Request) -> Result<Option<(&TokenSource, String)>, ApiError> {
+        for source in self.sources.iter() {
+            if let Some(token) = source.extract(req) {
+                return Ok(Some((source, token)));
+            }
+        }
+
+        // Credentials we can't read aren't the same as no credentials
+        let bearer = self.sources.contains(&TokenSource::Bearer);
+        if bearer && req.headers().get_raw("Authorization").is_some() {
+            let error = ErrorKind::MalformedAuthHeader;
+            let challenge = WwwAuthenticate::bearer(self.realm.as_str())
+                .error(BearerError::InvalidRequest, &error);
+            return Err(ApiError::from(error).with_header(challenge));
+        }
+        Ok(None)
+    }
+
+    /// Ask the client to authenticate
+    fn missing(&self) -> ApiError {
+        let challenge = WwwAuthenticate::bearer(self.realm.as_str());
+        ApiError::from(ErrorKind::AuthHeaderMissing).with_header(challenge)
+    }
+
+    /// Tell the client why its token has been rejected
+    ///
+    /// Failures on our side are no reason to re-authenticate and aren't challenged
+    fn rejected(&self, e: &token::Error) -> ApiError {
+        let error = ApiError::from(e.kind());
+        if error.status_code() != StatusCode::Unauthorized {
+            return error;
+        }
 
-        Ok(&bearer.token)
+        let challenge = WwwAuthenticate::bearer(self.realm.as_str())
+            .error(BearerError::InvalidToken, e.kind());
+        error.with_header(challenge)
     }
 }
 
-impl Middleware for Authenticator {
+impl<C> Middleware for Authenticator<C>
+where
+    C: DeserializeOwned + Clone + Send + Sync + 'static,
+{
     #[async(boxed)]
-    fn handle(self: Box<Self>, mut req: Request) -> TransitionResult {
+    fn handle(self: Box<Self>, req: Request, ctx: Context) -> TransitionResult {
         trace!("accepted {} request for {}", req.method(), req.uri());
         trace!("headers: {:?}", req.headers());
 
-        // Extract Token from headers
-        let token = match Self::extract_token(&req) {
-            Ok(token) => token.to_owned(),
+        // Extract Token from the configured sources
+        let (source, token) = match self.extract_token(&req) {
+            Ok(Some((source, token))) => (source.clone(), token),
+            Ok(None) if self.optional => {
+                debug!("anonymous request to {}", req.path());
+                return Ok(Transition::Request(req));
+            },
+            Ok(None) => return Ok(Transition::errored(self.missing())),
             Err(error) => return Ok(Transition::errored(error)),
         };
 
-        let auth_result = await!(self.auth.authenticate(token));
+        let auth_result = await!(self.auth.verified(token).and_then(|token| token.typed::<C>()));
 
         match auth_result {
             Err(e) => {
-                debug!("attempted unathorized access to {}", req.path());
-                Ok(Transition::errored(e))
+                debug!("attempted unathorized access to {} with {}", req.path(), source);
+                Ok(Transition::errored(self.rejected(&e)))
             },
             Ok(token) => {
-                debug!("authorized request from user {}", token.user_id());
+                debug!(
+                    "authorized request from user {} with {} from {}",
+                    token.user_id(),
+                    token.scheme,
+                    source
+                );
 
-                // Set UserID header
-                let uid = token.user_id().to_owned();
-                req.headers_mut().set(UserID(uid));
+                ctx.insert(UserID(token.user_id().to_owned()));
+                ctx.insert(token);
 
                 Ok(Transition::Request(req))
             },
         }
     }
-}
\ No newline at end of file
+}
+
+/// Value of a query parameter, percent-decoded
+fn query_param(query: &str, name: &str) -> Option<String> {
+    query
+        .split('&')
+        .filter_map(|pair| {
+            let mut pair = pair.splitn(2, '=');
+            match (pair.next(), pair.next()) {
+                (Some(key), Some(value)) if key == name => Some(value),
+                _ => None,
+            }
+        })
+        .next()
+        .and_then(percent_decode)
+}
+
+fn percent_decode(value: &str) -> Option<String> {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = value.get(i + 1..i + 3)?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use token::ErrorKind as TokenErrorKind;
+    use token::verifier::FutureVerified;
+    use futures::future;
+    use hyper::Method;
+    use tokio_core::reactor::Core;
+
+    struct Rejecting;
+
+    impl Authority for Rejecting {
+        fn verified(&self, _token: String) -> FutureVerified {
+            box future::err(TokenErrorKind::TokenInactive.into())
+        }
+    }
+
+    #[test]
+    fn token_sources_are_tried_in_order() {
+        let auth: Authenticator = Authenticator::new(Rejecting).sources(vec![
+            TokenSource::Cookie("session".to_owned()),
+            TokenSource::Query("access_token".to_owned()),
+            TokenSource::ApiKey,
+            TokenSource::Bearer,
+        ]);
+
+        let uri = "/events?stream=1&access_token=a%2Bb.c".parse().unwrap();
+        let mut req = Request::new(Method::Get, uri);
+        req.headers_mut().set(ApiKey("api-key".to_owned()));
+        let (source, token) = auth.extract_token(&req).unwrap().unwrap();
+        assert_eq!(*source, TokenSource::Query("access_token".to_owned()));
+        assert_eq!(token, "a+b.c");
+
+        let mut cookie = Cookie::new();
+        cookie.append("session", "cookie-token");
+        req.headers_mut().set(cookie);
+        let (source, token) = auth.extract_token(&req).unwrap().unwrap();
+        assert_eq!(*source, TokenSource::Cookie("session".to_owned()));
+        assert_eq!(token, "cookie-token");
+
+        let req = Request::new(Method::Get, "/events".parse().unwrap());
+        assert!(auth.extract_token(&req).unwrap().is_none());
+        assert!(auth.missing().headers().has::<WwwAuthenticate>());
+    }
+
+    #[test]
+    fn optional_authentication() {
+        let auth = box Authenticator::<NoClaims>::new(Rejecting).optional();
+        let mut core = Core::new().unwrap();
+
+        let req = Request::new(Method::Get, "/feed".parse().unwrap());
+        let ctx = Context::new();
+        match core.run(auth.clone().handle(req, ctx.clone())).unwrap() {
+            Transition::Request(..) => (),
+            Transition::Response(..) => panic!("anonymous request rejected"),
+        }
+        assert!(!ctx.contains::<UserID>());
+
+        let mut req = Request::new(Method::Get, "/feed".parse().unwrap());
+        req.headers_mut().set(Authorization(Bearer { token: "invalid".to_owned() }));
+        match core.run(auth.clone().handle(req, Context::new())).unwrap() {
+            Transition::Response(response) => {
+                assert_eq!(response.status(), StatusCode::Unauthorized)
+            },
+            Transition::Request(..) => panic!("invalid token accepted"),
+        }
+
+        // Credentials of another scheme aren't taken for none
+        let mut req = Request::new(Method::Get, "/feed".parse().unwrap());
+        req.headers_mut().set_raw("Authorization", "Basic dXNlcjpwYXNz");
+        match core.run(auth.handle(req, Context::new())).unwrap() {
+            Transition::Response(response) => {
+                assert_eq!(response.status(), StatusCode::Unauthorized);
+                let challenge = response.headers().get::<WwwAuthenticate>().unwrap();
+                assert_eq!(challenge.error, Some(BearerError::InvalidRequest));
+            },
+            Transition::Request(..) => panic!("malformed credentials passed as anonymous"),
+        }
+    }
+}
@@ -0,0 +1,81 @@
+//! Request body size limit middleware
+
+use http::Context;
+use http::body::MaxBodySize;
+use http::error::ErrorKind;
+
+use hyper::Request;
+
+use futures::prelude::*;
+
+use std::str;
+
+use http::middleware::{
+    Middleware,
+    Transition,
+    TransitionResult
+};
+
+/// Middleware limiting the size of request bodies on a route
+///
+/// Requests declaring a longer or an invalid `Content-Length` get 413 right away.
+/// Handlers must read bodies with `http::body::read` to enforce the limit
+/// for bodies without a length, i.e. chunked ones
+#[derive(Debug, Copy, Clone)]
+pub struct BodyLimit {
+    limit: usize,
+}
+
+impl BodyLimit {
+    /// Limit bodies to `limit` bytes
+    pub fn new(limit: usize) -> Self {
+        BodyLimit { limit }
+    }
+}
+
+impl Middleware for BodyLimit {
+    #[async(boxed)]
+    fn handle(self: Box<Self>, req: Request, ctx: Context) -> TransitionResult {
+        let declared = req.headers().get_raw("Content-Length").map(|raw| {
+            raw.one()
+                .and_then(|length| str::from_utf8(length).ok())
+                .and_then(|length| length.trim().parse::<u64>().ok())
+        });
+
+        match declared {
+            Some(None) => return Ok(Transition::errored(ErrorKind::InvalidContentLength)),
+            Some(Some(length)) if length > self.limit as u64 => {
+                return Ok(Transition::errored(ErrorKind::PayloadTooLarge(self.limit)))
+            },
+            _ => (),
+        }
+
+        ctx.insert(MaxBodySize(self.limit));
+        Ok(Transition::Request(req))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::{Method, StatusCode};
+    use tokio_core::reactor::Core;
+
+    fn status(length: &'static str) -> Option<StatusCode> {
+        let mut req = Request::new(Method::Post, "/token/refresh".parse().unwrap());
+        req.headers_mut().set_raw("Content-Length", length);
+
+        let mut core = Core::new().unwrap();
+        match core.run((box BodyLimit::new(1024)).handle(req, Context::new())).unwrap() {
+            Transition::Response(response) => Some(response.status()),
+            Transition::Request(..) => None,
+        }
+    }
+
+    #[test]
+    fn declared_length_is_checked() {
+        assert_eq!(status("512"), None);
+        assert_eq!(status("4096"), Some(StatusCode::PayloadTooLarge));
+        assert_eq!(status("lots"), Some(StatusCode::PayloadTooLarge));
+    }
+}
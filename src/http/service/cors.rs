@@ -0,0 +1,349 @@
+//! Cross-Origin Resource Sharing
+
+use http::Context;
+
+use hyper::{Method, Request, Response, StatusCode};
+use hyper::header::{
+    AccessControlAllowCredentials,
+    AccessControlAllowMethods,
+    AccessControlAllowOrigin,
+    AccessControlMaxAge,
+    AccessControlRequestMethod,
+    ContentLength,
+    Headers,
+};
+
+use futures::future::ok;
+use futures::prelude::*;
+
+use std::rc::Rc;
+use std::str;
+use std::time::Duration;
+
+use http::middleware::{
+    FutureResponse,
+    Middleware,
+    Transition,
+    TransitionResult
+};
+
+/// Origin allowed to make cross-origin requests
+#[derive(Debug, Clone, Eq, PartialEq)]
+enum AllowedOrigin {
+    Any,
+    Exact(String),
+    /// Subdomains of a domain with the scheme: `https://*.example.com`
+    Subdomains { scheme: String, domain: String },
+}
+
+impl AllowedOrigin {
+    fn parse(origin: &str) -> Self {
+        if origin == "*" {
+            return AllowedOrigin::Any;
+        }
+        match origin.find("://*.") {
+            Some(idx) => AllowedOrigin::Subdomains {
+                scheme: origin[..idx].to_owned(),
+                domain: origin[idx + "://*".len()..].to_owned(),
+            },
+            None => AllowedOrigin::Exact(origin.to_owned()),
+        }
+    }
+
+    fn matches(&self, origin: &str) -> bool {
+        match *self {
+            AllowedOrigin::Any => true,
+            AllowedOrigin::Exact(ref allowed) => allowed == origin,
+            AllowedOrigin::Subdomains { ref scheme, ref domain } => {
+                let prefix = format!("{}://", scheme);
+                origin.starts_with(&prefix) && origin.ends_with(domain.as_str()) &&
+                    origin.len() > prefix.len() + domain.len()
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct CorsConfig {
+    origins: Vec<AllowedOrigin>,
+    methods: Vec<Method>,
+    headers: Vec<String>,
+    exposed: Vec<String>,
+    credentials: bool,
+    max_age: Option<u32>,
+}
+
+/// Origin of an allowed cross-origin request, kept in the context for the response
+#[derive(Debug, Clone)]
+struct CorsOrigin(String);
+
+/// CORS middleware answering preflight requests and decorating actual responses
+///
+/// Chain it after the `AccessLog` and the `RequestTimeout` of the cross-origin routes,
+/// before the `RateLimiter` and the `Authenticator` so their rejections are readable too.
+/// In the chains of their `OPTIONS` routes chain it right after the `AccessLog`.
+/// Requests from other origins pass unchanged and are left for browsers to block
+#[derive(Debug, Clone)]
+pub struct Cors {
+    config: Rc<CorsConfig>,
+}
+
+impl Cors {
+    /// Allow no origins yet, simple methods and the `Authorization` and `Content-Type` headers
+    pub fn new() -> Self {
+        Cors {
+            config: Rc::new(CorsConfig {
+                origins: vec![],
+                methods: vec![Method::Get, Method::Head, Method::Post],
+                headers: vec!["authorization".to_owned(), "content-type".to_owned()],
+                exposed: vec![],
+                credentials: false,
+                max_age: None,
+            }),
+        }
+    }
+
+    /// Allow an origin: `https://app.example.com`, `https://*.example.com` or `*`
+    ///
+    /// Panics on `*` if credentials are allowed
+    pub fn allow_origin(mut self, origin: &str) -> Self {
+        let origin = AllowedOrigin::parse(origin);
+        assert!(
+            origin != AllowedOrigin::Any || !self.config.credentials,
+            "credentials can't be allowed for any origin"
+        );
+        Rc::make_mut(&mut self.config).origins.push(origin);
+        self
+    }
+
+    pub fn allow_methods(mut self, methods: Vec<Method>) -> Self {
+        Rc::make_mut(&mut self.config).methods = methods;
+        self
+    }
+
+    /// Request headers cross-origin requests may have
+    pub fn allow_headers(mut self, headers: &[&str]) -> Self {
+        Rc::make_mut(&mut self.config).headers = lowercase(headers);
+        self
+    }
+
+    /// Response headers cross-origin scripts may read
+    pub fn expose_headers(mut self, headers: &[&str]) -> Self {
+        Rc::make_mut(&mut self.config).exposed = lowercase(headers);
+        self
+    }
+
+    /// Let cross-origin requests carry cookies
+    ///
+    /// Panics if any origin is allowed: every site could then act on behalf of the users
+    pub fn allow_credentials(mut self) -> Self {
+        assert!(
+            !self.config.origins.contains(&AllowedOrigin::Any),
+            "credentials can't be allowed for any origin"
+        );
+        Rc::make_mut(&mut self.config).credentials = true;
+        self
+    }
+
+    /// Time browsers may cache preflight responses for
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        Rc::make_mut(&mut self.config).max_age = Some(max_age.as_secs() as u32);
+        self
+    }
+
+    fn allowed_origin(&self, headers: &Headers) -> Option<String> {
+        let origin = headers.get_raw("Origin")?.one()?;
+        let origin = str::from_utf8(origin).ok()?;
+        if self.config.origins.iter().any(|allowed| allowed.matches(origin)) {
+            Some(origin.to_owned())
+        } else {
+            None
+        }
+    }
+
+    fn preflight(&self, req: &Request) -> Response {
+        let mut response = Response::new()
+            .with_status(StatusCode::NoContent)
+            .with_header(ContentLength(0));
+
+        let origin = match self.allowed_origin(req.headers()) {
+            Some(origin) => origin,
+            None => return response,
+        };
+
+        let method_allowed = match req.headers().get::<AccessControlRequestMethod>() {
+            Some(method) => self.config.methods.contains(&method.0),
+            None => false,
+        };
+        let requested: Vec<String> = req.headers()
+            .get_raw("Access-Control-Request-Headers")
+            .map(|raw| {
+                raw.iter()
+                    .filter_map(|line| str::from_utf8(line).ok())
+                    .flat_map(|line| line.split(','))
+                    .map(|header| header.trim().to_lowercase())
+                    .filter(|header| !header.is_empty())
+                    .collect()
+            })
+            .unwrap_or_else(Vec::new);
+        let headers_allowed = requested.iter().all(|header| self.config.headers.contains(header));
+
+        if !method_allowed || !headers_allowed {
+            debug!("rejected preflight from {}", origin);
+            return response;
+        }
+
+        self.allow(response.headers_mut(), origin);
+        let headers = response.headers_mut();
+        headers.set(AccessControlAllowMethods(self.config.methods.clone()));
+        if !self.config.headers.is_empty() {
+            headers.set_raw("Access-Control-Allow-Headers", self.config.headers.join(", "));
+        }
+        if let Some(max_age) = self.config.max_age {
+            headers.set(AccessControlMaxAge(max_age));
+        }
+        response
+    }
+
+    fn allow(&self, headers: &mut Headers, origin: String) {
+        headers.set(AccessControlAllowOrigin::Value(origin));
+        if self.config.credentials {
+            headers.set(AccessControlAllowCredentials);
+        }
+    }
+}
+
+impl Default for Cors {
+    fn default() -> Self {
+        Cors::new()
+    }
+}
+
+impl Middleware for Cors {
+    #[async(boxed)]
+    fn handle(self: Box<Self>, req: Request, ctx: Context) -> TransitionResult {
+        if req.method() == &Method::Options {
+            return Ok(Transition::Response(self.preflight(&req)));
+        }
+
+        if let Some(origin) = self.allowed_origin(req.headers()) {
+            ctx.insert(CorsOrigin(origin));
+        }
+        Ok(Transition::Request(req))
+    }
+
+    fn after(self: Box<Self>, mut response: Response, ctx: Context) -> FutureResponse {
+        {
+            let headers = response.headers_mut();
+            // Caches must not serve the response to one origin to another,
+            // preflight responses get it here too
+            headers.append_raw("Vary", "Origin");
+            if let Some(CorsOrigin(origin)) = ctx.remove::<CorsOrigin>() {
+                self.allow(headers, origin);
+                if !self.config.exposed.is_empty() {
+                    let exposed = self.config.exposed.join(", ");
+                    headers.set_raw("Access-Control-Expose-Headers", exposed);
+                }
+            }
+        }
+        box ok(response)
+    }
+}
+
+fn lowercase(headers: &[&str]) -> Vec<String> {
+    headers.iter().map(|header| header.to_lowercase()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::middleware::Chains;
+    use http::service::Health;
+    use hyper::server::Service;
+    use tokio_core::reactor::Core;
+
+    fn cors() -> Cors {
+        Cors::new()
+            .allow_origin("https://*.example.com")
+            .allow_methods(vec![Method::Get, Method::Post, Method::Delete])
+            .expose_headers(&["X-Request-ID"])
+            .allow_credentials()
+            .max_age(Duration::from_secs(600))
+    }
+
+    fn preflight(origin: &str, method: Method) -> Request {
+        let mut req = Request::new(Method::Options, "/restricted".parse().unwrap());
+        req.headers_mut().set_raw("Origin", origin.to_owned());
+        req.headers_mut().set(AccessControlRequestMethod(method));
+        req.headers_mut().set_raw("Access-Control-Request-Headers", "Authorization");
+        req
+    }
+
+    fn respond(req: Request) -> Response {
+        let mut core = Core::new().unwrap();
+        match core.run((box cors()).handle(req, Context::new())).unwrap() {
+            Transition::Response(response) => response,
+            Transition::Request(..) => panic!("preflight passed through"),
+        }
+    }
+
+    #[test]
+    fn preflight_requests() {
+        let response = respond(preflight("https://app.example.com", Method::Delete));
+        assert_eq!(response.status(), StatusCode::NoContent);
+        assert_eq!(
+            response.headers().get::<AccessControlAllowOrigin>(),
+            Some(&AccessControlAllowOrigin::Value("https://app.example.com".to_owned()))
+        );
+        assert_eq!(response.headers().get::<AccessControlMaxAge>(), Some(&AccessControlMaxAge(600)));
+
+        let response = respond(preflight("https://example.com.evil.org", Method::Get));
+        assert!(response.headers().get::<AccessControlAllowOrigin>().is_none());
+
+        let response = respond(preflight("https://app.example.com", Method::Put));
+        assert!(response.headers().get::<AccessControlAllowOrigin>().is_none());
+    }
+
+    #[test]
+    fn preflight_responses_vary_by_origin_once() {
+        let chains = Chains::builder().chain(box cors()).build();
+
+        let mut core = Core::new().unwrap();
+        let req = preflight("https://app.example.com", Method::Delete);
+        let response = core.run(chains.call(req)).unwrap();
+        assert_eq!(response.status(), StatusCode::NoContent);
+        assert_eq!(response.headers().get_raw("Vary").unwrap().len(), 1);
+        assert_eq!(response.headers().get_raw("Vary").unwrap(), "Origin");
+    }
+
+    #[test]
+    fn actual_responses_are_decorated() {
+        let chains = Chains::builder()
+            .chain(box cors())
+            .chain(box Health)
+            .build();
+
+        let mut core = Core::new().unwrap();
+        let mut req = Request::new(Method::Get, "/restricted".parse().unwrap());
+        req.headers_mut().set_raw("Origin", "https://app.example.com");
+        let response = core.run(chains.call(req)).unwrap();
+
+        assert!(response.headers().has::<AccessControlAllowCredentials>());
+        assert_eq!(
+            response.headers().get_raw("Access-Control-Expose-Headers").unwrap(),
+            "x-request-id"
+        );
+
+        // Responses without an origin vary by it as well
+        let req = Request::new(Method::Get, "/restricted".parse().unwrap());
+        let response = core.run(chains.call(req)).unwrap();
+        assert!(response.headers().get::<AccessControlAllowOrigin>().is_none());
+        assert_eq!(response.headers().get_raw("Vary").unwrap(), "Origin");
+    }
+
+    #[test]
+    #[should_panic(expected = "credentials can't be allowed for any origin")]
+    fn credentials_are_refused_for_any_origin() {
+        cors().allow_origin("*");
+    }
+}
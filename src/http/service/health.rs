@@ -2,6 +2,7 @@ use hyper::Request;
 
 use futures::prelude::*;
 
+use http::Context;
 use http::middleware::{
     Middleware,
     Transition,
@@ -14,7 +15,7 @@ pub struct Health;
 
 impl Middleware for Health {
     #[async(boxed)]
-    fn handle(self: Box<Self>, _req: Request) -> TransitionResult {
+    fn handle(self: Box<Self>, _req: Request, _ctx: Context) -> TransitionResult {
         Ok(Transition::success(HealthStatus::ok()))
     }
 }
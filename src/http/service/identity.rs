@@ -0,0 +1,141 @@
+//! Identity passed between our services in HMAC-signed headers
+
+use http::Context;
+use http::error::ErrorKind;
+use http::header::{SignedUserID, UserID};
+use token::unix_now;
+
+use hyper::Request;
+
+use futures::prelude::*;
+
+use openssl::error::ErrorStack;
+use openssl::memcmp;
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+
+use std::rc::Rc;
+use std::time::Duration;
+
+use http::middleware::{
+    Middleware,
+    Transition,
+    TransitionResult
+};
+
+/// Default time a signed identity is accepted for after signing: 1 minute
+const DEFAULT_MAX_AGE_SECS: i64 = 60;
+
+/// Signs and verifies `SignedUserID` headers with a secret shared between our services
+///
+/// The calling service signs the identity of its user for an internal request.
+/// As a middleware it turns a valid `SignedUserID` into the `UserID` in the `Context`
+/// and rejects invalid or expired ones. Requests without the header are passed as is
+#[derive(Clone)]
+pub struct SignedIdentity {
+    key: Rc<PKey>,
+    max_age: i64,
+}
+
+impl SignedIdentity {
+    /// Construct with the HMAC-SHA256 secret
+    pub fn new(secret: &[u8]) -> Result<Self, ErrorStack> {
+        Ok(SignedIdentity {
+            key: Rc::new(PKey::hmac(secret)?),
+            max_age: DEFAULT_MAX_AGE_SECS,
+        })
+    }
+
+    /// Time a signed identity is accepted for, to limit replays
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = max_age.as_secs() as i64;
+        self
+    }
+
+    /// Sign the user identity for an outgoing internal request
+    pub fn sign(&self, uid: &str) -> Result<SignedUserID, ErrorStack> {
+        let mut signed = SignedUserID {
+            uid: uid.to_owned(),
+            issued_at: unix_now(),
+            signature: vec![],
+        };
+        signed.signature = self.signature(&signed)?;
+        Ok(signed)
+    }
+
+    /// Check the signature and age of a signed identity
+    pub fn verify(&self, signed: &SignedUserID) -> bool {
+        let age = unix_now() - signed.issued_at;
+        if age < 0 || age > self.max_age {
+            return false;
+        }
+
+        match self.signature(signed) {
+            Ok(expected) => {
+                expected.len() == signed.signature.len() &&
+                    memcmp::eq(&expected, &signed.signature)
+            },
+            Err(_) => false,
+        }
+    }
+
+    fn signature(&self, signed: &SignedUserID) -> Result<Vec<u8>, ErrorStack> {
+        let mut signer = Signer::new(MessageDigest::sha256(), &self.key)?;
+        signer.update(signed.signed_data().as_bytes())?;
+        signer.sign_to_vec()
+    }
+}
+
+impl Middleware for SignedIdentity {
+    #[async(boxed)]
+    fn handle(self: Box<Self>, mut req: Request, ctx: Context) -> TransitionResult {
+        let signed = match req.headers_mut().remove::<SignedUserID>() {
+            Some(signed) => signed,
+            None => return Ok(Transition::Request(req)),
+        };
+
+        if !self.verify(&signed) {
+            debug!("rejected signed identity of user {}", signed.uid);
+            return Ok(Transition::errored(ErrorKind::InvalidSignedUserID));
+        }
+
+        ctx.insert(UserID(signed.uid));
+        Ok(Transition::Request(req))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_core::reactor::Core;
+    use hyper::{Method, StatusCode};
+
+    fn request(signed: SignedUserID) -> Request {
+        let mut req = Request::new(Method::Get, "/internal".parse().unwrap());
+        req.headers_mut().set(signed);
+        req
+    }
+
+    #[test]
+    fn signed_identity() {
+        let identity = box SignedIdentity::new(b"secret").unwrap();
+        let mut core = Core::new().unwrap();
+
+        let signed = identity.sign("12345").unwrap();
+        let ctx = Context::new();
+        match core.run(identity.clone().handle(request(signed.clone()), ctx.clone())).unwrap() {
+            Transition::Request(req) => assert!(req.headers().get::<SignedUserID>().is_none()),
+            Transition::Response(..) => panic!("valid identity rejected"),
+        }
+        assert_eq!(ctx.get::<UserID>(), Some(UserID("12345".to_owned())));
+
+        let forged = SignedUserID { uid: "someone-else".to_owned(), ..signed };
+        match core.run(identity.handle(request(forged), Context::new())).unwrap() {
+            Transition::Response(response) => {
+                assert_eq!(response.status(), StatusCode::Unauthorized)
+            },
+            Transition::Request(..) => panic!("forged identity accepted"),
+        }
+    }
+}
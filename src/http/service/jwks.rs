@@ -0,0 +1,100 @@
+//! `/.well-known/jwks.json` service publishing token verification keys
+
+use token::jwk::JwkSet;
+
+use hyper::{Request, Response};
+use hyper::header::{CacheControl, CacheDirective, ContentLength, ContentType};
+
+use futures::prelude::*;
+use json;
+
+use std::rc::Rc;
+use std::time::Duration;
+
+use http::Context;
+use http::middleware::{
+    Middleware,
+    Transition,
+    TransitionResult
+};
+
+/// Default time clients may cache the key set for: 1 hour
+const DEFAULT_MAX_AGE_SECS: u32 = 60 * 60;
+
+/// Service serving public keys as a JWKS document (RFC 7517)
+///
+/// The document is serialized once. To rotate keys without rejecting tokens
+/// signed with the previous key, publish both for at least `max_age`
+/// plus the lifetime of the tokens
+#[derive(Debug, Clone)]
+pub struct Jwks {
+    document: Rc<String>,
+    max_age: u32,
+}
+
+impl Jwks {
+    /// Construct the service publishing the key set
+    pub fn new(keys: &JwkSet) -> Self {
+        Jwks {
+            document: Rc::new(json::to_string(keys).unwrap()),
+            max_age: DEFAULT_MAX_AGE_SECS,
+        }
+    }
+
+    /// Time clients may cache the key set for
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = max_age.as_secs() as u32;
+        self
+    }
+}
+
+impl Middleware for Jwks {
+    #[async(boxed)]
+    fn handle(self: Box<Self>, _req: Request, _ctx: Context) -> TransitionResult {
+        let response = Response::new()
+            .with_header(ContentType::json())
+            .with_header(ContentLength(self.document.len() as u64))
+            .with_header(CacheControl(vec![
+                CacheDirective::Public,
+                CacheDirective::MaxAge(self.max_age),
+            ]))
+            .with_body((*self.document).clone());
+
+        Ok(Transition::Response(response))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use token::jwk::Jwk;
+    use token::stub::keypair;
+    use tokio_core::reactor::Core;
+    use hyper::{Method, StatusCode};
+
+    #[test]
+    fn serves_keys_with_caching_headers() {
+        let keys = JwkSet {
+            keys: vec![
+                Jwk::from_key("current", &keypair()).unwrap(),
+                Jwk::from_key("retired", &keypair()).unwrap(),
+            ],
+        };
+        let service = box Jwks::new(&keys).max_age(Duration::from_secs(600));
+
+        let mut core = Core::new().unwrap();
+        let request = Request::new(Method::Get, "/.well-known/jwks.json".parse().unwrap());
+        let response = match core.run(service.handle(request, Context::new())).unwrap() {
+            Transition::Response(response) => response,
+            Transition::Request(..) => panic!("request passed through"),
+        };
+
+        assert_eq!(response.status(), StatusCode::Ok);
+        let cache_control = response.headers().get::<CacheControl>().unwrap();
+        assert!(cache_control.contains(&CacheDirective::MaxAge(600)));
+
+        let body = core.run(response.body().concat2()).unwrap();
+        let served: JwkSet = json::from_slice(&body).unwrap();
+        assert_eq!(served.to_keys().unwrap().len(), 2);
+    }
+}
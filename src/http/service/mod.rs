@@ -2,8 +2,22 @@
 
 #[macro_use]
 mod router;
+mod access_log;
 mod auth;
+mod body_limit;
+mod cors;
 mod health;
+mod identity;
+mod jwks;
+mod rate_limit;
+mod timeout;
 
-pub use self::auth::Authenticator;
-pub use self::health::Health;
\ No newline at end of file
+pub use self::access_log::{AccessLog, LogFormat, ACCESS_LOG_TARGET};
+pub use self::auth::{Authenticator, TokenSource};
+pub use self::body_limit::BodyLimit;
+pub use self::cors::Cors;
+pub use self::health::Health;
+pub use self::identity::SignedIdentity;
+pub use self::jwks::Jwks;
+pub use self::rate_limit::{Hits, MemoryStore, PgStore, RateLimitStore, RateLimiter};
+pub use self::timeout::RequestTimeout;
\ No newline at end of file
@@ -0,0 +1,314 @@
+//! Rate limiting of clients with a sliding window
+
+use db::AsyncPgPool;
+use db::models::RateLimitWindow;
+use db::query;
+use http::Context;
+use http::error::{Error, ErrorKind};
+use http::header::UserID;
+use http::ApiError;
+use http::ServerResponse;
+
+use hyper::{Request, Response};
+use hyper::header::{Headers, RetryAfter};
+
+use futures::future::{self, ok};
+use futures::prelude::*;
+
+use std::cell::{Cell, RefCell};
+use std::cmp;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use http::middleware::{
+    FutureResponse,
+    Middleware,
+    Transition,
+    TransitionResult
+};
+
+/// Counters kept by a MemoryStore before forgetting the outdated ones, once per window
+const MEMORY_STORE_SWEEP_SIZE: usize = 100_000;
+
+/// Requests of a key within the current and the previous window
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Hits {
+    pub current: u64,
+    pub previous: u64,
+}
+
+pub type FutureHits = Box<Future<Item = Hits, Error = Error>>;
+
+/// Backend counting requests within fixed windows
+pub trait RateLimitStore {
+    /// Count a request of the key within the window starting at `window_start`
+    fn hit(&self, key: &str, window_start: u64, window_length: u64) -> FutureHits;
+}
+
+/// Counter of a key in a MemoryStore, with the window of the limiter counting it
+#[derive(Debug, Copy, Clone)]
+struct Counter {
+    window_start: u64,
+    window_length: u64,
+    hits: Hits,
+}
+
+/// Counters of a single instance of the service
+///
+/// May be shared by limiters with different windows
+#[derive(Clone, Default)]
+pub struct MemoryStore {
+    counters: Rc<RefCell<HashMap<String, Counter>>>,
+    /// Latest window start the counters were swept at
+    swept: Rc<Cell<u64>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        MemoryStore::default()
+    }
+}
+
+impl RateLimitStore for MemoryStore {
+    fn hit(&self, key: &str, window_start: u64, window_length: u64) -> FutureHits {
+        let mut counters = self.counters.borrow_mut();
+        // Nothing more gets outdated until the next window
+        if counters.len() >= MEMORY_STORE_SWEEP_SIZE && window_start > self.swept.get() {
+            // Counters of the current and the previous window of their own limiter are kept
+            counters.retain(|_, counter| {
+                counter.window_start + 2 * counter.window_length > window_start
+            });
+            self.swept.set(window_start);
+        }
+
+        let counter = counters.entry(key.to_owned()).or_insert(Counter {
+            window_start,
+            window_length,
+            hits: Hits { current: 0, previous: 0 },
+        });
+        if counter.window_start != window_start {
+            let previous = if counter.window_start + window_length == window_start {
+                counter.hits.current
+            } else {
+                0
+            };
+            *counter = Counter {
+                window_start,
+                window_length,
+                hits: Hits { current: 0, previous },
+            };
+        }
+        counter.hits.current += 1;
+
+        box future::ok(counter.hits)
+    }
+}
+
+/// Counters in PostgreSQL shared by all instances of the service
+#[derive(Clone)]
+pub struct PgStore {
+    db_conn: Rc<AsyncPgPool>,
+}
+
+impl PgStore {
+    pub fn new(db_conn: Rc<AsyncPgPool>) -> Self {
+        PgStore { db_conn }
+    }
+}
+
+impl RateLimitStore for PgStore {
+    fn hit(&self, key: &str, window_start: u64, window_length: u64) -> FutureHits {
+        let window = RateLimitWindow {
+            key: key.to_owned(),
+            window_start: window_start as i64,
+            hits: 1,
+        };
+        let hits = query::count_hits(&self.db_conn, window, window_length as i64)
+            .map(|(current, previous)| Hits {
+                current: current as u64,
+                previous: previous as u64,
+            })
+            .map_err(Error::from);
+        box hits
+    }
+}
+
+/// State of the client's limit, kept in the context for the response
+#[derive(Debug, Copy, Clone)]
+struct Quota {
+    limit: u64,
+    remaining: u64,
+    reset: u64,
+}
+
+impl Quota {
+    fn set_headers(&self, headers: &mut Headers) {
+        headers.set_raw("RateLimit-Limit", self.limit.to_string());
+        headers.set_raw("RateLimit-Remaining", self.remaining.to_string());
+        headers.set_raw("RateLimit-Reset", self.reset.to_string());
+    }
+}
+
+/// Middleware limiting requests of a client on a route
+///
+/// Clients are told apart by the `UserID` an `Authenticator` chained before
+/// has put into the context, or by the IP address for anonymous requests.
+/// Requests are counted within fixed windows, the previous window weighted
+/// by how much of it the sliding window still covers.
+/// Requests over the limit get 429 with `Retry-After`, all of them `RateLimit-*` headers.
+/// Requests pass if the store fails
+#[derive(Clone)]
+pub struct RateLimiter {
+    route: Rc<String>,
+    limit: u64,
+    window: u64,
+    store: Rc<RateLimitStore>,
+}
+
+impl RateLimiter {
+    /// Allow `limit` requests per `window` on the route, counted in the store
+    pub fn new<R, S>(route: R, limit: u64, window: Duration, store: S) -> Self
+    where
+        R: Into<String>,
+        S: RateLimitStore + 'static,
+    {
+        RateLimiter {
+            route: Rc::new(route.into()),
+            limit,
+            window: cmp::max(window.as_secs(), 1),
+            store: Rc::new(store),
+        }
+    }
+
+    fn key(&self, req: &Request, ctx: &Context) -> Option<String> {
+        if let Some(UserID(uid)) = ctx.get::<UserID>() {
+            return Some(format!("{}:user:{}", self.route, uid));
+        }
+        #[allow(deprecated)]
+        let client = req.remote_addr();
+        client.map(|addr| format!("{}:ip:{}", self.route, addr.ip()))
+    }
+
+    /// Estimate the requests within the sliding window ending now
+    fn quota(&self, hits: Hits, now: u64) -> (Quota, bool) {
+        let elapsed = now % self.window;
+        let weight = (self.window - elapsed) as f64 / self.window as f64;
+        let estimate = (hits.previous as f64 * weight).floor() as u64 + hits.current;
+
+        let quota = Quota {
+            limit: self.limit,
+            remaining: self.limit.saturating_sub(estimate),
+            reset: self.window - elapsed,
+        };
+        (quota, estimate <= self.limit)
+    }
+}
+
+impl Middleware for RateLimiter {
+    #[async(boxed)]
+    fn handle(self: Box<Self>, req: Request, ctx: Context) -> TransitionResult {
+        let key = match self.key(&req, &ctx) {
+            Some(key) => key,
+            None => return Ok(Transition::Request(req)),
+        };
+
+        let now = unix_time();
+        let window_start = now - now % self.window;
+        let hits = match await!(self.store.hit(&key, window_start, self.window)) {
+            Ok(hits) => hits,
+            Err(e) => {
+                error!("failed to count requests of {}: {}", key, e);
+                return Ok(Transition::Request(req));
+            },
+        };
+
+        let (quota, allowed) = self.quota(hits, now);
+        if !allowed {
+            debug!("rate limited {}", key);
+            let error = ApiError::from(ErrorKind::TooManyRequests)
+                .with_header(RetryAfter::Delay(Duration::from_secs(quota.reset)));
+            let mut response: Response = ServerResponse::from(error).into();
+            quota.set_headers(response.headers_mut());
+            return Ok(Transition::Response(response));
+        }
+
+        ctx.insert(quota);
+        Ok(Transition::Request(req))
+    }
+
+    fn after(self: Box<Self>, mut response: Response, ctx: Context) -> FutureResponse {
+        if let Some(quota) = ctx.remove::<Quota>() {
+            quota.set_headers(response.headers_mut());
+        }
+        box ok(response)
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::{Method, StatusCode};
+    use tokio_core::reactor::Core;
+
+    fn request(limiter: &RateLimiter, core: &mut Core) -> Transition {
+        let req = Request::new(Method::Get, "/restricted".parse().unwrap());
+        let ctx = Context::new();
+        ctx.insert(UserID("12345".to_owned()));
+        core.run((box limiter.clone()).handle(req, ctx)).unwrap()
+    }
+
+    #[test]
+    fn requests_over_limit_are_rejected() {
+        let limiter = RateLimiter::new("restricted", 2, Duration::from_secs(3600), MemoryStore::new());
+        let mut core = Core::new().unwrap();
+
+        for _ in 0..2 {
+            if let Transition::Response(..) = request(&limiter, &mut core) {
+                panic!("request within limit rejected");
+            }
+        }
+        match request(&limiter, &mut core) {
+            Transition::Response(response) => {
+                assert_eq!(response.status(), StatusCode::TooManyRequests);
+                assert!(response.headers().has::<RetryAfter>());
+                assert_eq!(response.headers().get_raw("RateLimit-Remaining").unwrap(), "0");
+            },
+            Transition::Request(..) => panic!("request over limit passed"),
+        }
+    }
+
+    #[test]
+    fn sweep_keeps_counters_of_longer_windows() {
+        let store = MemoryStore::new();
+        store.hit("hourly:ip:127.0.0.1", 0, 3600).wait().unwrap();
+
+        for i in 0..MEMORY_STORE_SWEEP_SIZE {
+            store.hit(&format!("minutely:ip:{}", i), 60, 60).wait().unwrap();
+        }
+        store.hit("minutely:ip:127.0.0.1", 180, 60).wait().unwrap();
+
+        let hits = store.hit("hourly:ip:127.0.0.1", 0, 3600).wait().unwrap();
+        assert_eq!(hits, Hits { current: 2, previous: 0 });
+    }
+
+    #[test]
+    fn previous_window_is_weighted() {
+        let limiter = RateLimiter::new("restricted", 10, Duration::from_secs(60), MemoryStore::new());
+        let hits = Hits { current: 4, previous: 10 };
+
+        // A quarter into the window, three quarters of the previous one still count
+        let (quota, allowed) = limiter.quota(hits, 600 + 15);
+        assert!(!allowed);
+        assert_eq!(quota.remaining, 0);
+        assert_eq!(quota.reset, 45);
+
+        let (quota, allowed) = limiter.quota(hits, 600 + 45);
+        assert!(allowed);
+        assert_eq!(quota.remaining, 4);
+    }
+}
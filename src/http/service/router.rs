@@ -13,7 +13,11 @@ macro_rules! router {
         use http::HandlerService;
         use http::ServerResponse;
         use http::error::ErrorKind;
-        use futures::future::ok;
+        use http::header::RequestID;
+        use http::middleware::accept;
+        use http::request_id;
+        use futures::Future;
+        use futures::future::{lazy, ok};
 
         use std::rc::Rc;
         use std::io;
@@ -43,7 +47,7 @@ macro_rules! router {
             type Response = Response;
             type Error = hyper::Error;
             type Future = FutureHandled;
-            fn call(&self, req: Request) -> Self::Future {
+            fn call(&self, mut req: Request) -> Self::Future {
                 $(
                     if req.method() == &$method
                     && req.path() == $path {
@@ -51,12 +55,14 @@ macro_rules! router {
                     }
                 )*
 
-                box ok(ServerResponse::from(
-                    ErrorKind::PathNotFound(
-                        req.method().clone(),
-                        req.path().to_owned()
-                    )).into()
-                )
+                // Requests no route takes are accepted the way `Chains` does
+                let id = accept(&mut req);
+                let error = ErrorKind::PathNotFound(req.method().clone(), req.path().to_owned());
+                let header = RequestID(id.clone());
+                box request_id::scope(id, lazy(move || {
+                    debug!("{}", error);
+                    ok::<Response, hyper::Error>(ServerResponse::from(error).into())
+                }).map(move |response: Response| response.with_header(header)))
             }
         }
 
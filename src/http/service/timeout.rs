@@ -0,0 +1,93 @@
+//! Request timeout middleware
+
+use http::Context;
+use http::deadline::Deadline;
+
+use hyper::Request;
+use tokio_core::reactor::Handle;
+
+use futures::prelude::*;
+
+use std::time::Duration;
+
+use http::middleware::{
+    Middleware,
+    Transition,
+    TransitionResult
+};
+
+/// Middleware limiting the time the rest of the chain may take to respond
+///
+/// Requests the chain doesn't respond to in time get 504, a failure of the timer 500.
+/// Chain it second, right after the `AccessLog` and before `Cors`, with a timeout per route.
+/// An earlier deadline set before is kept. Handlers can see the remaining time
+/// of the `Deadline` in the context
+#[derive(Clone)]
+pub struct RequestTimeout {
+    timeout: Duration,
+    handle: Handle,
+}
+
+impl RequestTimeout {
+    /// Construct with the timeout timed on the event loop of the handle
+    pub fn new(timeout: Duration, handle: &Handle) -> Self {
+        RequestTimeout {
+            timeout,
+            handle: handle.clone(),
+        }
+    }
+}
+
+impl Middleware for RequestTimeout {
+    #[async(boxed)]
+    fn handle(self: Box<Self>, req: Request, ctx: Context) -> TransitionResult {
+        let deadline = Deadline::after(self.timeout, &self.handle);
+        let earlier = ctx.with(|set: &Deadline| set.at() <= deadline.at()).unwrap_or(false);
+        if !earlier {
+            ctx.insert(deadline);
+        }
+        Ok(Transition::Request(req))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::middleware::{Chains, FutureTransition};
+    use futures::future;
+    use hyper::{Method, StatusCode};
+    use hyper::server::Service;
+    use tokio_core::reactor::{self, Core};
+
+    /// Responds once its delay has passed, if there's enough time left
+    #[derive(Clone)]
+    struct Slow(Duration, Handle);
+
+    impl Middleware for Slow {
+        fn handle(self: Box<Self>, _req: Request, ctx: Context) -> FutureTransition {
+            let remaining = ctx.with(Deadline::remaining).unwrap();
+            assert!(remaining <= Duration::from_millis(100));
+
+            let delay = reactor::Timeout::new(self.0, &self.1).unwrap();
+            box delay.then(|_| future::ok(Transition::success("done")))
+        }
+    }
+
+    fn respond(delay: Duration) -> StatusCode {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let chains = Chains::builder()
+            .chain(box RequestTimeout::new(Duration::from_millis(100), &handle))
+            .chain(box Slow(delay, handle))
+            .build();
+
+        let request = Request::new(Method::Get, "/slow".parse().unwrap());
+        core.run(chains.call(request)).unwrap().status()
+    }
+
+    #[test]
+    fn slow_chains_time_out() {
+        assert_eq!(respond(Duration::from_millis(10)), StatusCode::Ok);
+        assert_eq!(respond(Duration::from_secs(5)), StatusCode::GatewayTimeout);
+    }
+}
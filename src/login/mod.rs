@@ -1,38 +1,103 @@
 use db::AsyncPgPool;
-use db::query::*;
-use http;
-use http::FutureHandled;
-use http::ServerResponse;
-use http::header::UserID;
+use http::Context;
+use http::body;
+use http::error::ErrorKind;
+use http::middleware::{Middleware, Transition, TransitionResult};
+use token::{Scheme, SessionIssuer, Token};
 
-use futures::{Future, Stream};
-use futures::future::ok;
+use futures::prelude::*;
 
-use hyper;
-use hyper::{Method, Request, Response};
-use hyper::server::Service;
+use hyper::Request;
 
 use json;
 
 use std::rc::Rc;
 
+/// Claims of a Firebase ID token the login needs besides the user id
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LoginClaims {
+    pub auth_time: i64,
+}
+
+#[derive(Debug, Deserialize)]
+struct RefreshRequest {
+    refresh_token: String,
+}
+
+/// `/login` handler exchanging a Firebase ID token for a session
+///
+/// Chained after an `Authenticator<LoginClaims>`
+#[derive(Clone)]
 pub struct LoginHandler {
     db_conn: Rc<AsyncPgPool>,
+    sessions: Rc<SessionIssuer>,
 }
 
 impl LoginHandler {
-    pub fn new(db_conn: Rc<AsyncPgPool>) -> Self {
-        LoginHandler { db_conn }
+    pub fn new(db_conn: Rc<AsyncPgPool>, sessions: Rc<SessionIssuer>) -> Self {
+        LoginHandler { db_conn, sessions }
+    }
+}
+
+impl Middleware for LoginHandler {
+    #[async(boxed)]
+    fn handle(self: Box<Self>, _req: Request, ctx: Context) -> TransitionResult {
+        let token = match ctx.remove::<Token<LoginClaims>>() {
+            Some(token) => token,
+            None => return Ok(Transition::errored(ErrorKind::MissingUserIDHeader)),
+        };
+
+        // Sessions must not prolong themselves: only Firebase users log in
+        if token.scheme != Scheme::IdToken {
+            return Ok(Transition::errored(ErrorKind::LoginRequiresIdToken));
+        }
+        let uid = token.user_id().to_owned();
+        let auth_time = token.claims.auth_time;
+
+        match await!(self.sessions.start(&self.db_conn, &uid, auth_time)) {
+            Ok(session) => {
+                debug!("started session for user {}", uid);
+                Ok(Transition::success(session))
+            },
+            Err(e) => Ok(Transition::errored(e)),
+        }
+    }
+}
+
+/// `/token/refresh` handler exchanging a refresh token for a new session
+#[derive(Clone)]
+pub struct RefreshHandler {
+    db_conn: Rc<AsyncPgPool>,
+    sessions: Rc<SessionIssuer>,
+}
+
+impl RefreshHandler {
+    pub fn new(db_conn: Rc<AsyncPgPool>, sessions: Rc<SessionIssuer>) -> Self {
+        RefreshHandler { db_conn, sessions }
     }
 }
 
-impl Service for LoginHandler {
-    type Request = Request;
-    type Response = Response;
-    type Error = hyper::Error;
-    type Future = FutureHandled;
+impl Middleware for RefreshHandler {
+    #[async(boxed)]
+    fn handle(self: Box<Self>, req: Request, ctx: Context) -> TransitionResult {
+        let body = match await!(body::read(req, &ctx)) {
+            Ok(body) => body,
+            Err(e) => return Ok(Transition::errored(e)),
+        };
+        let request: RefreshRequest = match json::from_slice(&body) {
+            Ok(request) => request,
+            Err(e) => {
+                let error = ErrorKind::InvalidRequestBody(e.to_string());
+                return Ok(Transition::errored(error));
+            },
+        };
 
-    fn call(&self, mut req: Request) -> Self::Future {
-        unimplemented!()
+        match await!(self.sessions.refresh(&self.db_conn, &request.refresh_token)) {
+            Ok(session) => Ok(Transition::success(session)),
+            Err(e) => {
+                debug!("refresh rejected: {}", e);
+                Ok(Transition::errored(e))
+            },
+        }
     }
 }
@@ -10,6 +10,7 @@ extern crate chrono;
 extern crate r2d2;
 extern crate r2d2_postgres;
 extern crate postgres;
+extern crate fallible_iterator;
 
 extern crate futures_await as futures;
 extern crate futures_cpupool;
@@ -20,6 +21,7 @@ extern crate fern;
 extern crate hyper;
 extern crate reqwest;
 extern crate openssl;
+extern crate base64;
 
 #[macro_use]
 extern crate error_chain;
@@ -39,20 +41,33 @@ mod login;
 use db::AsyncPgPool;
 
 use futures::Stream;
+use http::service::AccessLog;
 use http::service::Authenticator;
+use http::service::BodyLimit;
+use http::service::Cors;
+use http::service::TokenSource;
 use http::service::Health;
+use http::service::Jwks;
+use http::service::RequestTimeout;
+use http::service::{PgStore, RateLimiter};
 
 use hyper::server::Http;
 use hyper::server::NewService;
 
+use std::env;
 use std::rc::Rc;
 use std::sync::mpsc::channel;
 use std::thread;
+use std::time::Duration;
 
 use tokio_core::net::TcpListener;
 use tokio_core::reactor;
 
-use login::LoginHandler;
+use login::{LoginClaims, LoginHandler, RefreshHandler};
+
+use token::{AsyncTokenVerifier, RevocationList, SessionIssuer, StaticKeys, TokenVerifier};
+use token::ValidationPolicy;
+use token::keyring::GOOGLE_KEYRING_URL;
 
 use http::middleware::Chains;
 
@@ -65,17 +80,20 @@ fn init_logger() -> Result<(), log::SetLoggerError> {
 
     fern::Dispatch::new()
         .format(|out, message, record| {
+            let request = http::request_id::current().map(|id| format!("[{}]", id));
             out.finish(format_args!(
-                "{}[{}][{}] {}",
+                "{}[{}][{}]{} {}",
                 chrono::Local::now().format("[%Y-%m-%d][%H:%M:%S]"),
                 record.target(),
                 record.level(),
+                request.unwrap_or_default(),
                 message
             ))
         })
         .level(log::LogLevelFilter::Warn)
         .level_for("service", log::LogLevelFilter::Trace)
         .level_for("common", log::LogLevelFilter::Trace)
+        .level_for(http::service::ACCESS_LOG_TARGET, log::LogLevelFilter::Info)
         .chain(tx)
         .apply()?;
     Ok(())
@@ -96,16 +114,138 @@ fn main() {
     let mut core = reactor::Core::new().expect("Failed to initialize event loop");
     let handle = core.handle();
 
+    // Token verification keys: Google Keyring unless pointed elsewhere (i.e. a local JWKS file)
+    // @TODO read key source from config file
+    let key_source = env::var("TOKEN_KEYS").unwrap_or(GOOGLE_KEYRING_URL.to_owned());
+    let keys = token::source::open(&key_source).expect("Failed to load token verification keys");
+
+    // Only tokens issued for our Firebase project are accepted
+    // @TODO read project id and leeway from config file
+    let project_id = env::var("FIREBASE_PROJECT_ID").expect("FIREBASE_PROJECT_ID is not set");
+    let policy = ValidationPolicy::firebase(&project_id).leeway(Duration::from_secs(30));
+    let mut verifier = TokenVerifier::new(keys, policy);
+
+    // Internal services call us with HMAC tokens signed with a shared secret
+    // @TODO read the secret from config file
+    if let Ok(secret) = env::var("SERVICE_SECRET") {
+        let secrets = StaticKeys::shared_secret(secret.as_bytes())
+            .expect("Failed to construct a shared secret key");
+        let policy = ValidationPolicy::service(env!("CARGO_PKG_NAME")).leeway(Duration::from_secs(30));
+        verifier = verifier.with_shared_secrets(secrets, policy);
+    }
+
+    // Additional OpenID Connect providers, i.e. an enterprise IdP,
+    // as comma-separated `issuer-url=audience` pairs
+    // @TODO read issuers from config file
+    if let Ok(issuers) = env::var("OIDC_ISSUERS") {
+        for entry in issuers.split(',').filter(|entry| !entry.is_empty()) {
+            let mut pair = entry.rsplitn(2, '=');
+            let audience = pair.next().unwrap();
+            let issuer = pair.next().expect("OIDC_ISSUERS entries must be issuer-url=audience");
+            let policy = ValidationPolicy::new().audience(audience).leeway(Duration::from_secs(30));
+            verifier = verifier
+                .with_oidc_issuer(issuer, policy)
+                .expect("Failed to discover an OpenID Connect provider");
+        }
+    }
+
+    // Our own session tokens issued after login with a Firebase ID token
+    // @TODO read session key path and lifetimes from config file
+    let session_key = env::var("SESSION_KEY").expect("SESSION_KEY is not set");
+    let mut sessions = SessionIssuer::from_file(env!("CARGO_PKG_NAME"), project_id.as_str(), &session_key)
+        .expect("Failed to load the session signing key");
+
+    // The previous key is kept while access tokens signed with it may still be in use
+    if let Ok(retired_key) = env::var("SESSION_RETIRED_KEY") {
+        sessions = sessions
+            .with_retired_key_file(&retired_key)
+            .expect("Failed to load the retired session key");
+    }
+    let session_keys = sessions.verification_keys().expect("Failed to derive session verification keys");
+    verifier = verifier.with_session_keys(sessions.issuer(), session_keys, sessions.policy());
+    let jwks = Jwks::new(&sessions.jwks().expect("Failed to publish session keys"));
+
+    // Revoked tokens are rejected even if they are still cached as verified,
+    // revoked users can't refresh their sessions either
+    let revocations = RevocationList::listen(&pgpool).expect("Failed to load the revocation list");
+    let sessions = Rc::new(sessions.with_revocation_list(revocations.clone()));
+    let verifier = AsyncTokenVerifier::new(verifier).with_revocation_list(revocations);
+
     // Authenticator for token verification and user info population in the database
-    let authenticator = Authenticator::new();
+    // Browsers can't set headers on every request: accept cookies and query parameters too
+    let authenticator: Authenticator = Authenticator::new(verifier).sources(vec![
+        TokenSource::Bearer,
+        TokenSource::ApiKey,
+        TokenSource::Cookie("access_token".to_owned()),
+        TokenSource::Query("access_token".to_owned()),
+    ]);
+    let login_authenticator: Authenticator<LoginClaims> = authenticator.with_claims();
+
+    // One line per completed request, in front of every chain
+    let access_log = AccessLog::default();
+
+    // The web frontend calls us from its own origins, comma-separated
+    // @TODO read origins from config file
+    let mut cors = Cors::new()
+        .allow_headers(&["Authorization", "Content-Type", "X-Request-ID", "X-API-Key"])
+        .expose_headers(&["X-Request-ID", "WWW-Authenticate"])
+        .allow_credentials()
+        .max_age(Duration::from_secs(600));
+    for origin in env::var("CORS_ORIGINS").unwrap_or_default().split(',') {
+        if !origin.is_empty() {
+            cors = cors.allow_origin(origin);
+        }
+    }
+    // Sessions are started and refreshed rarely: limit guessing and abuse,
+    // counting in the database since we run several instances.
+    // Logins are limited by IP before their tokens are verified
+    // @TODO read limits from config file
+    let rate_limits = PgStore::new(pgpool.clone());
+    let login_limit = RateLimiter::new("login", 10, Duration::from_secs(60), rate_limits.clone());
+    let refresh_limit = RateLimiter::new("refresh", 30, Duration::from_secs(60), rate_limits);
+
+    // Nothing is worth waiting for longer, i.e. a database stuck
+    // @TODO read timeouts from config file
+    let timeout = RequestTimeout::new(Duration::from_secs(10), &handle);
+
+    let preflight = Rc::new(Chains::builder()
+        .chain(Box::new(access_log))
+        .chain(Box::new(cors.clone()))
+        .build());
 
     // Router to dispatch requests for concrete pathes to their handlers
     let router = router!(
-        post_login:     Method::Post, "/login"      => Rc::new(LoginHandler::new(pgpool.clone())),
+        post_login:     Method::Post, "/login"      => Rc::new(Chains::builder()
+            .chain(Box::new(access_log))
+            .chain(Box::new(timeout.clone()))
+            .chain(Box::new(cors.clone()))
+            .chain(Box::new(login_limit))
+            .chain(Box::new(login_authenticator))
+            .chain(Box::new(BodyLimit::new(4 * 1024)))
+            .chain(Box::new(LoginHandler::new(pgpool.clone(), sessions.clone())))
+            .build()),
+        post_refresh:   Method::Post, "/token/refresh" => Rc::new(Chains::builder()
+            .chain(Box::new(access_log))
+            .chain(Box::new(timeout.clone()))
+            .chain(Box::new(cors.clone()))
+            .chain(Box::new(refresh_limit))
+            .chain(Box::new(BodyLimit::new(4 * 1024)))
+            .chain(Box::new(RefreshHandler::new(pgpool.clone(), sessions.clone())))
+            .build()),
+        get_jwks:       Method::Get,  "/.well-known/jwks.json" => Rc::new(Chains::builder()
+            .chain(Box::new(access_log))
+            .chain(Box::new(jwks))
+            .build()),
         restricted:     Method::Get,  "/restricted" => Rc::new(Chains::builder()
+            .chain(Box::new(access_log))
+            .chain(Box::new(timeout.clone()))
+            .chain(Box::new(cors.clone()))
             .chain(Box::new(authenticator))
             .chain(Box::new(Health))
             .build()),
+        options_login:      Method::Options, "/login"         => preflight.clone(),
+        options_refresh:    Method::Options, "/token/refresh" => preflight.clone(),
+        options_restricted: Method::Options, "/restricted"    => preflight,
     );
 
     // Starting TCP server listening for incoming commections
@@ -0,0 +1,114 @@
+//! Token signature algorithms and authentication schemes
+
+use token::{Result, ErrorKind};
+
+use openssl::hash::MessageDigest;
+
+use std::fmt;
+use std::str::FromStr;
+
+/// Supported JWT signature algorithms
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Algorithm {
+    /// RSASSA-PKCS1-v1_5 with SHA-256, used by Firebase
+    RS256,
+    /// HMAC with SHA-256
+    HS256,
+    /// HMAC with SHA-384
+    HS384,
+    /// HMAC with SHA-512
+    HS512,
+}
+
+/// Scheme a token has been authenticated with
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Scheme {
+    /// Identity token signed with a public key pair, i.e. a Firebase ID token
+    IdToken,
+    /// Service token signed with a secret shared between our services
+    SharedSecret,
+    /// Access token issued by this service after login, signed with our own key pair
+    Session,
+    /// Opaque token checked at an introspection endpoint (RFC 7662)
+    Introspection,
+}
+
+impl Algorithm {
+    /// Parse the `alg` header value
+    pub fn from_name(alg: &str) -> Result<Self> {
+        match alg {
+            "RS256" => Ok(Algorithm::RS256),
+            "HS256" => Ok(Algorithm::HS256),
+            "HS384" => Ok(Algorithm::HS384),
+            "HS512" => Ok(Algorithm::HS512),
+            _ => bail!(ErrorKind::UnsupportedAlgorithm(alg.to_owned())),
+        }
+    }
+
+    /// Name of the algorithm as in the `alg` header
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Algorithm::RS256 => "RS256",
+            Algorithm::HS256 => "HS256",
+            Algorithm::HS384 => "HS384",
+            Algorithm::HS512 => "HS512",
+        }
+    }
+
+    /// Hash function of the algorithm
+    pub fn digest(&self) -> MessageDigest {
+        match *self {
+            Algorithm::RS256 | Algorithm::HS256 => MessageDigest::sha256(),
+            Algorithm::HS384 => MessageDigest::sha384(),
+            Algorithm::HS512 => MessageDigest::sha512(),
+        }
+    }
+
+    /// Scheme tokens signed with the algorithm belong to
+    pub fn scheme(&self) -> Scheme {
+        match *self {
+            Algorithm::RS256 => Scheme::IdToken,
+            Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => Scheme::SharedSecret,
+        }
+    }
+}
+
+impl Scheme {
+    /// Scheme name to be passed in headers and logs
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Scheme::IdToken => "id-token",
+            Scheme::SharedSecret => "shared-secret",
+            Scheme::Session => "session",
+            Scheme::Introspection => "introspection",
+        }
+    }
+
+    /// Whether tokens of the scheme may be signed with the algorithm
+    pub fn accepts(&self, alg: Algorithm) -> bool {
+        match *self {
+            Scheme::IdToken | Scheme::Session => alg.scheme() == Scheme::IdToken,
+            Scheme::SharedSecret => alg.scheme() == Scheme::SharedSecret,
+            Scheme::Introspection => false,
+        }
+    }
+}
+
+impl fmt::Display for Scheme {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Scheme {
+    type Err = ();
+    fn from_str(s: &str) -> ::std::result::Result<Self, ()> {
+        match s {
+            "id-token" => Ok(Scheme::IdToken),
+            "shared-secret" => Ok(Scheme::SharedSecret),
+            "session" => Ok(Scheme::Session),
+            "introspection" => Ok(Scheme::Introspection),
+            _ => Err(()),
+        }
+    }
+}
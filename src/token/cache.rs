@@ -0,0 +1,164 @@
+//! Bounded LRU cache of verified tokens
+
+use token::Token;
+
+use json;
+use openssl::sha::sha256;
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+/// SHA-256 of a raw token: raw tokens are never kept in memory
+pub type Digest = [u8; 32];
+
+/// Verified token with custom claims left as raw JSON
+pub type VerifiedToken = Arc<Token<json::Value>>;
+
+/// Hash a raw token into a cache key
+pub fn digest(token: &str) -> Digest {
+    sha256(token.as_bytes())
+}
+
+/// Cache hit and miss counters
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct CacheStats {
+    /// Tokens found in the cache
+    pub hits: u64,
+    /// Tokens that had to be verified
+    pub misses: u64,
+    /// Tokens that joined a verification already in flight
+    pub collapsed: u64,
+}
+
+/// Least recently used verified tokens, each kept until its `exp`
+pub struct TokenCache {
+    capacity: usize,
+    entries: HashMap<Digest, Entry>,
+    recency: BTreeMap<u64, Digest>,
+    tick: u64,
+}
+
+struct Entry {
+    token: VerifiedToken,
+    tick: u64,
+}
+
+impl TokenCache {
+    /// Construct a cache of at most `capacity` tokens. Zero capacity disables caching
+    pub fn new(capacity: usize) -> Self {
+        TokenCache {
+            capacity,
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            tick: 0,
+        }
+    }
+
+    /// Number of cached tokens
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Get a token unless it has expired by `now` (seconds since UNIX epoch)
+    pub fn get(&mut self, digest: &Digest, now: i64) -> Option<VerifiedToken> {
+        let expired = match self.entries.get(digest) {
+            Some(entry) => entry.token.exp <= now,
+            None => return None,
+        };
+
+        if expired {
+            self.remove(digest);
+            return None;
+        }
+
+        let tick = self.next_tick();
+        let entry = self.entries.get_mut(digest).unwrap();
+        self.recency.remove(&entry.tick);
+        self.recency.insert(tick, *digest);
+        entry.tick = tick;
+        Some(entry.token.clone())
+    }
+
+    /// Put a token into the cache, evicting the least recently used one if it's full
+    pub fn insert(&mut self, digest: Digest, token: VerifiedToken) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        self.remove(&digest);
+        while self.entries.len() >= self.capacity {
+            let oldest = match self.recency.iter().next() {
+                Some((_, digest)) => *digest,
+                None => break,
+            };
+            self.remove(&oldest);
+        }
+
+        let tick = self.next_tick();
+        self.recency.insert(tick, digest);
+        self.entries.insert(digest, Entry { token, tick });
+    }
+
+    fn remove(&mut self, digest: &Digest) {
+        if let Some(entry) = self.entries.remove(digest) {
+            self.recency.remove(&entry.tick);
+        }
+    }
+
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use token::{RegisteredClaims, Scheme};
+
+    fn token(exp: i64) -> VerifiedToken {
+        let registered: RegisteredClaims = json::from_str(&format!(
+            r#"{{"sub":"1","iat":0,"exp":{},"aud":"a","iss":"b"}}"#,
+            exp
+        )).unwrap();
+
+        Arc::new(Token {
+            scheme: Scheme::IdToken,
+            registered,
+            claims: json::Value::Null,
+        })
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut cache = TokenCache::new(2);
+        cache.insert(digest("a"), token(100));
+        cache.insert(digest("b"), token(100));
+
+        // "a" becomes the most recently used
+        assert!(cache.get(&digest("a"), 0).is_some());
+
+        cache.insert(digest("c"), token(100));
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get(&digest("a"), 0).is_some());
+        assert!(cache.get(&digest("b"), 0).is_none());
+        assert!(cache.get(&digest("c"), 0).is_some());
+    }
+
+    #[test]
+    fn expires() {
+        let mut cache = TokenCache::new(2);
+        cache.insert(digest("a"), token(100));
+
+        assert!(cache.get(&digest("a"), 99).is_some());
+        assert!(cache.get(&digest("a"), 100).is_none());
+        assert_eq!(cache.len(), 0);
+    }
+
+    #[test]
+    fn disabled() {
+        let mut cache = TokenCache::new(0);
+        cache.insert(digest("a"), token(100));
+        assert!(cache.get(&digest("a"), 0).is_none());
+    }
+}
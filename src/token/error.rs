@@ -7,6 +7,10 @@ use hyper::StatusCode;
 // Generate error types boilerplate
 
 error_chain! {
+    links {
+        Database(::db::Error, ::db::ErrorKind);
+    }
+
     foreign_links {
         Io(::std::io::Error);
         Json(::json::error::Error);
@@ -19,8 +23,13 @@ error_chain! {
 
     errors {
         FailedToRetrieveKeyring(status: StatusCode) {
-            description("failed to retrieve google keyring")
-            display("failed to retrieve google keyring: {}", status)
+            description("failed to retrieve keyring")
+            display("failed to retrieve keyring: {}", status)
+        }
+
+        FailedToDiscover(reason: String) {
+            description("failed to discover OpenID Connect provider")
+            display("failed to discover OpenID Connect provider: {}", reason)
         }
 
         EmptyUserID {
@@ -28,10 +37,121 @@ error_chain! {
             display("userid is empty")
         }
 
+        InvalidKeys(reason: String) {
+            description("invalid verification keys")
+            display("invalid verification keys: {}", reason)
+        }
+
         UnknownKeyID {
             description("unknown key id")
             display("unknown key id")
         }
+
+        MalformedToken(reason: &'static str) {
+            description("malformed token")
+            display("malformed token: {}", reason)
+        }
+
+        UnsupportedAlgorithm(alg: String) {
+            description("unsupported token signature algorithm")
+            display("unsupported token signature algorithm: {}", alg)
+        }
+
+        InvalidSignature {
+            description("invalid token signature")
+            display("invalid token signature")
+        }
+
+        TokenExpired {
+            description("token has expired")
+            display("token has expired")
+        }
+
+        TokenNotYetValid {
+            description("token is not valid yet")
+            display("token is not valid yet")
+        }
+
+        InvalidAudience(aud: String) {
+            description("token is issued for another audience")
+            display("token is issued for another audience: {}", aud)
+        }
+
+        InvalidIssuer(iss: String) {
+            description("token is issued by an untrusted issuer")
+            display("token is issued by an untrusted issuer: {}", iss)
+        }
+
+        AuthenticationTooOld {
+            description("user has authenticated too long ago")
+            display("user has authenticated too long ago")
+        }
+
+        MissingClaim(claim: String) {
+            description("token is missing a required claim")
+            display("token is missing a required claim: {}", claim)
+        }
+
+        TokenRevoked {
+            description("token has been revoked")
+            display("token has been revoked")
+        }
+
+        UserRevoked {
+            description("all tokens of the user have been revoked")
+            display("all tokens of the user have been revoked")
+        }
+
+        InvalidRefreshToken {
+            description("invalid or expired refresh token")
+            display("invalid or expired refresh token")
+        }
+
+        FailedToIntrospect(reason: String) {
+            description("failed to introspect token")
+            display("failed to introspect token: {}", reason)
+        }
+
+        TokenInactive {
+            description("token is not active")
+            display("token is not active")
+        }
+
+        RefreshTokenReused {
+            description("refresh token has already been used, the session is terminated")
+            display("refresh token has already been used, the session is terminated")
+        }
+    }
+}
+
+impl ErrorKind {
+    /// Reproduce the error kind for every waiter of a shared verification.
+    /// Foreign errors can't be cloned and are reduced to their messages
+    pub fn duplicate(&self) -> ErrorKind {
+        use token::ErrorKind::*;
+        match *self {
+            FailedToRetrieveKeyring(status) => FailedToRetrieveKeyring(status),
+            FailedToDiscover(ref reason) => FailedToDiscover(reason.clone()),
+            EmptyUserID => EmptyUserID,
+            InvalidKeys(ref reason) => InvalidKeys(reason.clone()),
+            UnknownKeyID => UnknownKeyID,
+            MalformedToken(reason) => MalformedToken(reason),
+            UnsupportedAlgorithm(ref alg) => UnsupportedAlgorithm(alg.clone()),
+            InvalidSignature => InvalidSignature,
+            TokenExpired => TokenExpired,
+            TokenNotYetValid => TokenNotYetValid,
+            InvalidAudience(ref aud) => InvalidAudience(aud.clone()),
+            InvalidIssuer(ref iss) => InvalidIssuer(iss.clone()),
+            AuthenticationTooOld => AuthenticationTooOld,
+            MissingClaim(ref claim) => MissingClaim(claim.clone()),
+            TokenRevoked => TokenRevoked,
+            UserRevoked => UserRevoked,
+            InvalidRefreshToken => InvalidRefreshToken,
+            RefreshTokenReused => RefreshTokenReused,
+            FailedToIntrospect(ref reason) => FailedToIntrospect(reason.clone()),
+            TokenInactive => TokenInactive,
+            ref foreign => Msg(foreign.to_string()),
+        }
     }
 }
 
@@ -53,6 +173,10 @@ impl<'a> From<&'a ErrorKind> for ApiError {
         use token::ErrorKind::*;
         match *ek {
             FailedToRetrieveKeyring(..) |
+            FailedToDiscover(..) |
+            InvalidKeys(..) |
+            FailedToIntrospect(..) |
+            Database(..) |
             Io(..) |
             Hyper(..) |
             OpenSSL(..) |
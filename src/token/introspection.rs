@@ -0,0 +1,301 @@
+//! OAuth 2.0 Token Introspection (RFC 7662) for opaque tokens
+
+use token::{Result, Error, ErrorKind};
+use token::{Scheme, Token, RegisteredClaims, ValidationPolicy};
+use token::cache::{self, TokenCache};
+use token::unix_now;
+use token::verifier::{Authority, FutureVerified};
+
+use futures::Future;
+use futures::future::{self, Either};
+use futures_cpupool::CpuPool;
+use json;
+use reqwest;
+use reqwest::header::{Authorization, Basic};
+use serde::de::DeserializeOwned;
+
+use std::cell::RefCell;
+use std::io::Read;
+use std::rc::Rc;
+use std::sync::Arc;
+
+/// Default number of introspected tokens kept by an AsyncIntrospector
+const DEFAULT_CACHE_CAPACITY: usize = 10_000;
+
+/// Introspector asks the authorization server whether a token is active
+///
+/// Claims returned by the server are validated according to the ValidationPolicy
+/// and kept as custom claims of the token
+pub struct Introspector {
+    endpoint: String,
+    credentials: Option<Basic>,
+    policy: ValidationPolicy,
+    client: reqwest::Client,
+}
+
+/// Introspection response members we rely on
+#[derive(Debug, Deserialize)]
+struct Introspection {
+    active: bool,
+    #[serde(default)]
+    sub: Option<String>,
+    #[serde(default)]
+    username: Option<String>,
+    #[serde(default)]
+    iat: Option<i64>,
+    #[serde(default)]
+    exp: Option<i64>,
+    #[serde(default)]
+    nbf: Option<i64>,
+    #[serde(default)]
+    aud: Option<json::Value>,
+    #[serde(default)]
+    iss: Option<String>,
+}
+
+impl Introspector {
+    /// Constructs an Introspector for the endpoint URL
+    pub fn new<U: Into<String>>(endpoint: U, policy: ValidationPolicy) -> Result<Self> {
+        Ok(Introspector {
+            endpoint: endpoint.into(),
+            credentials: None,
+            policy,
+            client: reqwest::Client::new()?,
+        })
+    }
+
+    /// Authenticate at the endpoint with client credentials (HTTP Basic)
+    pub fn with_client_credentials<I, S>(mut self, client_id: I, client_secret: S) -> Self
+    where
+        I: Into<String>,
+        S: Into<String>,
+    {
+        self.credentials = Some(Basic {
+            username: client_id.into(),
+            password: Some(client_secret.into()),
+        });
+        self
+    }
+
+    /// Introspect a token with custom claims of type `C`
+    ///
+    /// The `exp` of a token the server has reported no expiration time for
+    /// is the moment of introspection
+    pub fn introspect<C: DeserializeOwned>(&self, token: &str) -> Result<Token<C>> {
+        self.introspect_expiring(token).map(|(token, _)| token)
+    }
+
+    /// Introspect a token, telling whether it has an expiration time
+    fn introspect_expiring<C: DeserializeOwned>(&self, token: &str) -> Result<(Token<C>, bool)> {
+        let claims = self.request(token)?;
+        let introspection: Introspection = json::from_value(claims.clone()).map_err(|_| {
+            ErrorKind::FailedToIntrospect("malformed response".to_owned())
+        })?;
+
+        if !introspection.active {
+            bail!(ErrorKind::TokenInactive);
+        }
+        self.policy.validate_claims(&claims)?;
+
+        // `exp` is optional: the server vouches for the token just now
+        let expiring = introspection.exp.is_some();
+        let registered = self.registered_claims(introspection)?;
+        if expiring {
+            self.policy.validate(&registered, unix_now())?;
+        } else {
+            self.policy.validate_unexpiring(&registered, unix_now())?;
+        }
+
+        let claims: C = json::from_value(claims).map_err(|_| {
+            ErrorKind::MalformedToken("invalid custom claims")
+        })?;
+
+        let token = Token {
+            scheme: Scheme::Introspection,
+            registered,
+            claims,
+        };
+        Ok((token, expiring))
+    }
+
+    fn request(&self, token: &str) -> Result<json::Value> {
+        let params = [("token", token), ("token_type_hint", "access_token")];
+        let mut request = self.client.post(self.endpoint.as_str())?;
+        if let Some(ref credentials) = self.credentials {
+            request = request.header(Authorization(credentials.clone()));
+        }
+
+        let mut response = request.form(&params).send()?;
+        if !response.status().is_success() {
+            bail!(ErrorKind::FailedToIntrospect(response.status().to_string()));
+        }
+
+        let mut body = String::new();
+        response.read_to_string(&mut body)?;
+        json::from_str(&body).map_err(|_| {
+            ErrorKind::FailedToIntrospect("response is not a json".to_owned()).into()
+        })
+    }
+
+    /// Fill registered claims from the optional members of the response
+    fn registered_claims(&self, introspection: Introspection) -> Result<RegisteredClaims> {
+        let now = unix_now();
+        let uid = match introspection.sub.or(introspection.username) {
+            Some(uid) => uid,
+            None => bail!(ErrorKind::MissingClaim("sub".to_owned())),
+        };
+
+        // `aud` is either a string or an array of them
+        let aud = match introspection.aud {
+            Some(json::Value::String(aud)) => vec![aud],
+            Some(json::Value::Array(audiences)) => audiences
+                .into_iter()
+                .filter_map(|aud| aud.as_str().map(str::to_owned))
+                .collect(),
+            _ => vec![],
+        };
+
+        Ok(RegisteredClaims {
+            uid,
+            email: None,
+            iat: introspection.iat.unwrap_or(now),
+            exp: introspection.exp.unwrap_or(now),
+            nbf: introspection.nbf,
+            aud,
+            iss: introspection.iss.unwrap_or_default(),
+            auth_time: None,
+            service: None,
+        })
+    }
+}
+
+/// CpuPool driven introspection with the same API as AsyncTokenVerifier
+///
+/// Active tokens are cached until they expire, tokens without `exp` aren't.
+/// Lives on the event loop thread
+pub struct AsyncIntrospector {
+    cpupool: CpuPool,
+    introspector: Arc<Introspector>,
+    cache: Rc<RefCell<TokenCache>>,
+}
+
+impl AsyncIntrospector {
+    /// Constructs an AsyncIntrospector with an Introspector
+    /// and a CpuPool to run introspection requests
+    pub fn new(introspector: Introspector) -> Self {
+        AsyncIntrospector {
+            cpupool: CpuPool::new_num_cpus(),
+            introspector: Arc::new(introspector),
+            cache: Rc::new(RefCell::new(TokenCache::new(DEFAULT_CACHE_CAPACITY))),
+        }
+    }
+
+    /// Set the number of introspected tokens to keep. Zero disables caching
+    pub fn with_cache_capacity(self, capacity: usize) -> Self {
+        *self.cache.borrow_mut() = TokenCache::new(capacity);
+        self
+    }
+
+    /// Asynchronously introspect a token with custom claims of type `C`
+    pub fn authenticate<C>(&self, token: String) -> impl Future<Item = Token<C>, Error = Error>
+    where
+        C: DeserializeOwned + 'static,
+    {
+        self.verified(token).and_then(|token| token.typed())
+    }
+}
+
+impl Authority for AsyncIntrospector {
+    fn verified(&self, token: String) -> FutureVerified {
+        let digest = cache::digest(&token);
+        if let Some(token) = self.cache.borrow_mut().get(&digest, unix_now()) {
+            return box future::ok(token);
+        }
+
+        let introspector = self.introspector.clone();
+        let cache = self.cache.clone();
+        let introspection = self.cpupool
+            .spawn_fn(move || {
+                introspector
+                    .introspect_expiring::<json::Value>(&token)
+                    .map(|(token, expiring)| (Arc::new(token), expiring))
+            })
+            .map(move |(token, expiring)| {
+                if expiring && token.exp > unix_now() {
+                    cache.borrow_mut().insert(digest, token.clone());
+                }
+                token
+            });
+
+        box introspection
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use token::NoClaims;
+    use token::stub::StubServer;
+    use tokio_core::reactor::Core;
+
+    fn introspector(server: &StubServer) -> AsyncIntrospector {
+        let policy = ValidationPolicy::new().audience("circles");
+        let introspector = Introspector::new(server.url(), policy)
+            .unwrap()
+            .with_client_credentials("circles", "secret");
+        AsyncIntrospector::new(introspector)
+    }
+
+    #[test]
+    fn active_tokens_are_cached() {
+        let response = format!(
+            r#"{{"active":true,"sub":"12345","aud":["billing","circles"],"exp":{},"scope":"read"}}"#,
+            unix_now() + 3600
+        );
+        let server = StubServer::serve(response, vec![("Content-Type", "application/json".to_owned())]);
+        let introspector = introspector(&server);
+        let mut core = Core::new().unwrap();
+
+        for _ in 0..2 {
+            let token = core.run(introspector.authenticate::<json::Value>("opaque".to_owned()))
+                .unwrap();
+            assert_eq!(token.user_id(), "12345");
+            assert_eq!(token.aud, vec!["circles".to_owned()]);
+            assert_eq!(token.scheme, Scheme::Introspection);
+            assert_eq!(token.claims["scope"], "read");
+        }
+        assert_eq!(server.hits(), 1);
+    }
+
+    #[test]
+    fn active_tokens_without_exp_are_not_cached() {
+        let response = r#"{"active":true,"sub":"12345","aud":"circles"}"#;
+        let server = StubServer::serve(response.to_owned(), vec![]);
+        let introspector = introspector(&server);
+        let mut core = Core::new().unwrap();
+
+        for _ in 0..2 {
+            let token = core.run(introspector.authenticate::<NoClaims>("opaque".to_owned()))
+                .unwrap();
+            assert_eq!(token.user_id(), "12345");
+        }
+        assert_eq!(server.hits(), 2);
+    }
+
+    #[test]
+    fn inactive_tokens_are_rejected() {
+        let server = StubServer::serve(r#"{"active":false}"#.to_owned(), vec![]);
+        let introspector = introspector(&server);
+        let mut core = Core::new().unwrap();
+
+        for _ in 0..2 {
+            match *core.run(introspector.authenticate::<NoClaims>("opaque".to_owned()))
+                .unwrap_err()
+                .kind() {
+                ErrorKind::TokenInactive => (),
+                ref e => panic!("unexpected error: {}", e),
+            }
+        }
+        assert_eq!(server.hits(), 2);
+    }
+}
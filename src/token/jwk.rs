@@ -0,0 +1,106 @@
+//! JSON Web Keys (RFC 7517)
+
+use token::{Key, Result, ErrorKind};
+use token::source::Keys;
+
+use base64;
+use openssl::bn::BigNum;
+use openssl::pkey::{PKey, PKeyRef};
+use openssl::rsa::Rsa;
+
+use std::sync::Arc;
+
+/// Public RSA JSON Web Key
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Jwk {
+    /// Key type, only "RSA" keys are supported
+    pub kty: String,
+    /// Key ID
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kid: Option<String>,
+    /// Algorithm the key is intended for
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alg: Option<String>,
+    /// Intended use: "sig" for signatures
+    #[serde(rename = "use", default, skip_serializing_if = "Option::is_none")]
+    pub key_use: Option<String>,
+    /// RSA modulus, base64url encoded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub n: Option<String>,
+    /// RSA public exponent, base64url encoded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub e: Option<String>,
+}
+
+/// JSON Web Key Set
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct JwkSet {
+    /// Keys of the set
+    pub keys: Vec<Jwk>,
+}
+
+impl Jwk {
+    /// Describe public half of an RSA key as an RS256 signing JWK
+    pub fn from_key<K: Into<String>>(kid: K, key: &PKeyRef) -> Result<Jwk> {
+        let rsa = key.rsa()?;
+        let (n, e) = match (rsa.n(), rsa.e()) {
+            (Some(n), Some(e)) => (n.to_vec(), e.to_vec()),
+            _ => bail!(ErrorKind::InvalidKeys("RSA key without public components".to_owned())),
+        };
+
+        Ok(Jwk {
+            kty: "RSA".to_owned(),
+            kid: Some(kid.into()),
+            alg: Some("RS256".to_owned()),
+            key_use: Some("sig".to_owned()),
+            n: Some(base64::encode_config(&n, base64::URL_SAFE_NO_PAD)),
+            e: Some(base64::encode_config(&e, base64::URL_SAFE_NO_PAD)),
+        })
+    }
+
+    /// Construct a public key described by the JWK
+    pub fn to_key(&self) -> Result<Key> {
+        if self.kty != "RSA" {
+            bail!(ErrorKind::InvalidKeys(format!("unsupported key type {}", self.kty)));
+        }
+
+        let (n, e) = match (self.n.as_ref(), self.e.as_ref()) {
+            (Some(n), Some(e)) => (decode_component(n)?, decode_component(e)?),
+            _ => bail!(ErrorKind::InvalidKeys("RSA key without n or e".to_owned())),
+        };
+
+        let rsa = Rsa::from_public_components(BigNum::from_slice(&n)?, BigNum::from_slice(&e)?)?;
+        Ok(PKey::from_rsa(rsa)?)
+    }
+}
+
+impl JwkSet {
+    /// Convert the set into public keys mapped by key IDs
+    ///
+    /// Keys without an ID or of an unsupported type are skipped
+    pub fn to_keys(&self) -> Result<Keys> {
+        let mut keys = Keys::new();
+        for jwk in &self.keys {
+            let kid = match jwk.kid {
+                Some(ref kid) => kid.clone(),
+                None => {
+                    warn!("skipped JWK without key id");
+                    continue;
+                }
+            };
+
+            match jwk.to_key() {
+                Ok(key) => {
+                    keys.insert(kid, Arc::new(key));
+                }
+                Err(e) => warn!("skipped JWK {}: {}", kid, e),
+            }
+        }
+        Ok(keys)
+    }
+}
+
+fn decode_component(component: &str) -> Result<Vec<u8>> {
+    base64::decode_config(component, base64::URL_SAFE_NO_PAD)
+        .map_err(|_| ErrorKind::InvalidKeys("invalid base64 in JWK".to_owned()).into())
+}
@@ -0,0 +1,278 @@
+//! Self-refreshing remote Keyring
+
+use token::{Key, Result, ErrorKind};
+use token::source::{self, Keys};
+
+use reqwest;
+use reqwest::header::{CacheControl, CacheDirective};
+
+use std::cmp;
+use std::io::Read;
+use std::sync::{Arc, Condvar, Mutex, RwLock, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Google endpoint serving x509 certificates Firebase ID tokens are signed with
+pub const GOOGLE_KEYRING_URL: &str =
+    "https://www.googleapis.com/robot/v1/metadata/x509/securetoken@system.gserviceaccount.com";
+
+/// Refresh interval used when the endpoint does not send `Cache-Control: max-age`
+const DEFAULT_MAX_AGE_SECS: u64 = 60 * 60;
+/// Delay before retrying a failed background refresh
+const RETRY_DELAY_SECS: u64 = 30;
+/// Minimal interval between refreshes forced by an unknown key ID
+const FORCED_REFRESH_INTERVAL_SECS: u64 = 60;
+/// Minimal interval between background refreshes, whatever the `max-age`
+const MIN_MAX_AGE_SECS: u64 = 60;
+
+/// Keyring of public keys mapped by `kid`
+///
+/// Keys are refreshed in a background thread when the `Cache-Control: max-age`
+/// of the last response runs out. The thread stops once the Keyring is dropped.
+pub struct Keyring {
+    inner: Arc<KeyringInner>,
+}
+
+struct KeyringInner {
+    url: String,
+    keys: RwLock<Keys>,
+    last_refresh: Mutex<Instant>,
+    expiry: Arc<Expiry>,
+}
+
+impl Keyring {
+    /// Retrieve the Keyring Google signs Firebase ID tokens with
+    pub fn google() -> Result<Self> {
+        Self::new(GOOGLE_KEYRING_URL)
+    }
+
+    /// Retrieve a Keyring served by the `url` either as a JWKS document
+    /// or as a `{ kid: x509 pem }` map the way Google does
+    pub fn new<U: Into<String>>(url: U) -> Result<Self> {
+        let url = url.into();
+        let (keys, max_age) = fetch(&url)?;
+        info!("retrieved keyring of {} keys from {}", keys.len(), url);
+
+        let expiry = Arc::new(Expiry::default());
+        let inner = Arc::new(KeyringInner {
+            url,
+            keys: RwLock::new(keys),
+            last_refresh: Mutex::new(Instant::now()),
+            expiry: expiry.clone(),
+        });
+        spawn_refresher(Arc::downgrade(&inner), expiry, max_age);
+
+        Ok(Keyring { inner })
+    }
+
+    /// Get a key by its ID
+    ///
+    /// An unknown ID may mean Google has rotated its keys ahead of time,
+    /// so the keyring is refreshed, but no more often than once a minute
+    pub fn get(&self, kid: &str) -> Result<Arc<Key>> {
+        if let Some(key) = self.inner.lookup(kid) {
+            return Ok(key);
+        }
+
+        if self.inner.force_refresh() {
+            if let Some(key) = self.inner.lookup(kid) {
+                return Ok(key);
+            }
+        }
+
+        bail!(ErrorKind::UnknownKeyID)
+    }
+}
+
+impl KeyringInner {
+    fn lookup(&self, kid: &str) -> Option<Arc<Key>> {
+        self.keys.read().unwrap().get(kid).cloned()
+    }
+
+    fn refresh(&self) -> Result<Duration> {
+        let mut last_refresh = self.last_refresh.lock().unwrap();
+        let (keys, max_age) = fetch(&self.url)?;
+        *self.keys.write().unwrap() = keys;
+        *last_refresh = Instant::now();
+        Ok(max_age)
+    }
+
+    /// Refresh keys unless it was done recently. Returns whether keys were refreshed
+    fn force_refresh(&self) -> bool {
+        let mut last_refresh = self.last_refresh.lock().unwrap();
+        if last_refresh.elapsed() < Duration::from_secs(FORCED_REFRESH_INTERVAL_SECS) {
+            return false;
+        }
+
+        // Failed attempts count too, so a dead endpoint isn't hammered
+        *last_refresh = Instant::now();
+        match fetch(&self.url) {
+            Ok((keys, _)) => {
+                debug!("forced keyring refresh from {}", self.url);
+                *self.keys.write().unwrap() = keys;
+                true
+            }
+            Err(e) => {
+                warn!("forced keyring refresh failed: {}", e);
+                false
+            }
+        }
+    }
+}
+
+/// Wake the refresher thread up, so it stops right away
+impl Drop for KeyringInner {
+    fn drop(&mut self) {
+        self.expiry.expire();
+    }
+}
+
+/// Wakes the refresher thread when the keys expire
+#[derive(Default)]
+struct Expiry {
+    expired: Mutex<bool>,
+    condvar: Condvar,
+}
+
+impl Expiry {
+    /// Wait until the keys expire after `delay`, or sooner if expired by hand
+    fn wait(&self, delay: Duration) {
+        let deadline = Instant::now() + delay;
+        let mut expired = self.expired.lock().unwrap();
+        while !*expired {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            expired = self.condvar.wait_timeout(expired, deadline - now).unwrap().0;
+        }
+        *expired = false;
+    }
+
+    /// Expire the keys right away
+    fn expire(&self) {
+        *self.expired.lock().unwrap() = true;
+        self.condvar.notify_one();
+    }
+}
+
+fn spawn_refresher(keyring: Weak<KeyringInner>, expiry: Arc<Expiry>, max_age: Duration) {
+    thread::spawn(move || {
+        let mut delay = max_age;
+        loop {
+            expiry.wait(delay);
+
+            let keyring = match keyring.upgrade() {
+                Some(keyring) => keyring,
+                None => break,
+            };
+
+            delay = match keyring.refresh() {
+                Ok(max_age) => {
+                    debug!("refreshed keyring from {}", keyring.url);
+                    max_age
+                }
+                Err(e) => {
+                    warn!("failed to refresh keyring: {}", e);
+                    Duration::from_secs(RETRY_DELAY_SECS)
+                }
+            };
+        }
+        debug!("keyring dropped, refresher thread stopped");
+    });
+}
+
+/// Fetch keys and their lifetime from the certificates endpoint
+fn fetch(url: &str) -> Result<(Keys, Duration)> {
+    let mut response = reqwest::get(url)?;
+    if !response.status().is_success() {
+        bail!(ErrorKind::FailedToRetrieveKeyring(response.status()));
+    }
+
+    let max_age = response
+        .headers()
+        .get::<CacheControl>()
+        .and_then(|cc| {
+            cc.iter()
+                .filter_map(|directive| match *directive {
+                    CacheDirective::MaxAge(secs) => Some(secs as u64),
+                    _ => None,
+                })
+                .next()
+        })
+        .unwrap_or(DEFAULT_MAX_AGE_SECS);
+    // `max-age=0` must not make the refresher hammer the endpoint
+    let max_age = cmp::max(max_age, MIN_MAX_AGE_SECS);
+
+    let mut body = String::new();
+    response.read_to_string(&mut body)?;
+    let keys = source::parse_keys(&body)?;
+
+    Ok((keys, Duration::from_secs(max_age)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use token::stub::{self, StubServer};
+
+    #[test]
+    fn retrieve_keys() {
+        let server = StubServer::serve(stub::certificates(&["a", "b"]), vec![]);
+        let keyring = Keyring::new(server.url()).unwrap();
+
+        assert!(keyring.get("a").is_ok());
+        assert!(keyring.get("b").is_ok());
+        assert_eq!(server.hits(), 1);
+    }
+
+    #[test]
+    fn unknown_key_refresh_is_rate_limited() {
+        let server = StubServer::serve(stub::certificates(&["a"]), vec![]);
+        let keyring = Keyring::new(server.url()).unwrap();
+
+        for _ in 0..3 {
+            match *keyring.get("c").unwrap_err().kind() {
+                ErrorKind::UnknownKeyID => (),
+                ref e => panic!("unexpected error: {}", e),
+            }
+        }
+
+        // Keys were just fetched, so no forced refresh is allowed yet
+        assert_eq!(server.hits(), 1);
+    }
+
+    #[test]
+    fn refresh_when_expired() {
+        let server = StubServer::serve(
+            stub::certificates(&["a"]),
+            vec![("Cache-Control", "public, max-age=3600".to_owned())],
+        );
+        let keyring = Keyring::new(server.url()).unwrap();
+        assert!(keyring.get("a").is_ok());
+
+        server.set_body(stub::certificates(&["b"]));
+        keyring.inner.expiry.expire();
+
+        // The refresher thread fetches the keys in the background
+        for _ in 0..100 {
+            if keyring.inner.lookup("b").is_some() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(keyring.inner.lookup("b").is_some());
+        assert!(keyring.inner.lookup("a").is_none());
+        assert_eq!(server.hits(), 2);
+    }
+
+    #[test]
+    fn max_age_has_a_floor() {
+        let server = StubServer::serve(
+            stub::certificates(&["a"]),
+            vec![("Cache-Control", "no-cache, max-age=0".to_owned())],
+        );
+        let (_, max_age) = fetch(&server.url()).unwrap();
+        assert_eq!(max_age, Duration::from_secs(MIN_MAX_AGE_SECS));
+    }
+}
@@ -1,36 +1,462 @@
-//! Token decoding and verigication
-//! @TODO: testspub mod error;
+//! Token decoding and verification
 
+pub mod algorithm;
+pub mod cache;
 pub mod error;
+pub mod introspection;
+pub mod jwk;
+pub mod keyring;
+pub mod oidc;
+pub mod policy;
+pub mod revocation;
+pub mod session;
+pub mod source;
 pub mod verifier;
 
+#[cfg(test)]
+pub mod stub;
+
 // Export main elements
+pub use self::algorithm::{Algorithm, Scheme};
 pub use self::error::{Result, Error, ErrorKind};
-pub use self::verifier::{Key, TokenVerifier, AsyncTokenVerifier};
+pub use self::introspection::{Introspector, AsyncIntrospector};
+pub use self::keyring::Keyring;
+pub use self::policy::ValidationPolicy;
+pub use self::revocation::RevocationList;
+pub use self::session::{Session, SessionIssuer};
+pub use self::source::{KeySource, StaticKeys};
+pub use self::verifier::{Key, Authority, TokenVerifier, AsyncTokenVerifier};
 
+use base64;
 use json;
+use openssl::memcmp;
+use openssl::pkey::PKeyRef;
+use openssl::sign::{Signer, Verifier};
+use serde::{Deserialize, Deserializer, Serialize};
+use serde::de::DeserializeOwned;
+
 use std::ops::Deref;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// JOSE Header of a JWT
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Header {
+    /// Signature algorithm
+    pub alg: String,
+    /// ID of the key the token was signed with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kid: Option<String>,
+}
+
+impl Header {
+    /// Decode only the header of a JWT Token to find out which key it was signed with
+    pub fn decode(token: &str) -> Result<Header> {
+        RawToken::split(token)?.header()
+    }
+}
+
+/// Claims read before the signature is verified: only fit to pick the keys to verify it with
+#[derive(Clone, Debug, Deserialize)]
+pub struct UnverifiedClaims {
+    /// Issuer the token claims to come from
+    #[serde(default)]
+    pub iss: Option<String>,
+}
+
+impl UnverifiedClaims {
+    /// Decode the claims of a JWT Token without verifying it
+    pub fn decode(token: &str) -> Result<UnverifiedClaims> {
+        RawToken::split(token)?.claims()
+    }
+}
+
+/// Registered claims of a Firebase ID token
+#[derive(Clone, Debug, Deserialize)]
+pub struct RegisteredClaims {
+    /// User unique identifier from Google Firebase API (`sub` claim)
+    #[serde(rename = "sub")]
+    pub uid: String,
+    /// User email
+    #[serde(default)]
+    pub email: Option<String>,
+    /// Issue time, seconds since UNIX epoch
+    pub iat: i64,
+    /// Expiration time, seconds since UNIX epoch
+    pub exp: i64,
+    /// Time before which the token must not be accepted, seconds since UNIX epoch
+    #[serde(default)]
+    pub nbf: Option<i64>,
+    /// Audiences: Firebase project ID, sent as a string or an array of them
+    #[serde(deserialize_with = "one_or_many")]
+    pub aud: Vec<String>,
+    /// Issuer: `https://securetoken.google.com/<project-id>`
+    pub iss: String,
+    /// Authentication time, seconds since UNIX epoch
+    #[serde(default)]
+    pub auth_time: Option<i64>,
+    /// Identity of the calling service in service-to-service tokens
+    #[serde(rename = "svc", default)]
+    pub service: Option<String>,
+}
+
+/// Deserialize a claim that may be either a single string or an array of them
+fn one_or_many<'de, D>(deserializer: D) -> ::std::result::Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(value) => vec![value],
+        OneOrMany::Many(values) => values,
+    })
+}
+
+/// Custom claims placeholder for tokens without any
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct NoClaims {}
+
+/// Authorization Token with user-defined custom claims
+///
+/// Custom claims are deserialized from the same claims object as the registered ones,
+/// so `C` should only describe the fields it's interested in.
+/// Registered claims are accessible directly through `Deref`
+///
+/// # Examples
+///
+/// ```
+/// #[macro_use]
+/// extern crate serde_derive;
+/// extern crate service;
+///
+/// use service::token::Token;
+///
+/// #[derive(Clone, Debug, Deserialize)]
+/// struct Claims {
+///     roles: Vec<String>,
+///     tenant_id: Option<String>,
+/// }
+///
+/// fn is_admin(token: &Token<Claims>) -> bool {
+///     token.claims.roles.iter().any(|role| role == "admin")
+/// }
+/// # fn main() {}
+/// ```
+#[derive(Clone, Debug)]
+pub struct Token<C = NoClaims> {
+    /// Scheme the token has been authenticated with
+    pub scheme: Scheme,
+    /// Registered claims
+    pub registered: RegisteredClaims,
+    /// Custom claims
+    pub claims: C,
+}
+
+impl<C: DeserializeOwned> Token<C> {
+    /// Decode and verify the base64 encode JWT Token signed within the scheme
+    /// using provided key and validate its claims according to the policy
+    ///
+    /// The key is a public key for `Scheme::IdToken` and `Scheme::Session`
+    /// and an HMAC key for `Scheme::SharedSecret`
+    pub fn decode(
+        token: &str,
+        scheme: Scheme,
+        key: &PKeyRef,
+        policy: &ValidationPolicy,
+    ) -> Result<Self> {
+        let raw = RawToken::split(token)?;
+
+        // Never let the token choose how it is verified
+        let header = raw.header()?;
+        let alg = Algorithm::from_name(&header.alg)?;
+        if !scheme.accepts(alg) {
+            bail!(ErrorKind::UnsupportedAlgorithm(header.alg));
+        }
+
+        raw.verify_signature(alg, key)?;
 
-/// Authorization Token
-#[derive(Copy, Clone, Debug)]
-pub struct Token {
-    // TODO: add fields
+        let claims: json::Value = raw.claims()?;
+        policy.validate_claims(&claims)?;
+
+        let registered: RegisteredClaims = json::from_value(claims.clone()).map_err(|_| {
+            ErrorKind::MalformedToken("invalid claims")
+        })?;
+        policy.validate(&registered, unix_now())?;
+
+        let claims: C = json::from_value(claims).map_err(|_| {
+            ErrorKind::MalformedToken("invalid custom claims")
+        })?;
+
+        Ok(Token {
+            scheme,
+            registered,
+            claims,
+        })
+    }
 }
 
-impl Token {
-    /// Decode and verify the base64 encode JWT Token using provided Keyring
-    pub fn decode(token: &str, key: &Key) -> Result<Token> {
-        /* todo */
-        unimplemented!()
+impl Token<json::Value> {
+    /// Deserialize raw custom claims of a verified token into claims of type `C`
+    pub fn typed<C: DeserializeOwned>(&self) -> Result<Token<C>> {
+        let claims = json::from_value(self.claims.clone()).map_err(|_| {
+            ErrorKind::MalformedToken("invalid custom claims")
+        })?;
+
+        Ok(Token {
+            scheme: self.scheme,
+            registered: self.registered.clone(),
+            claims,
+        })
     }
+}
 
+impl<C> Token<C> {
     /// Get user unique identifier
     pub fn user_id(&self) -> &str {
-        unimplemented!()
+        &self.registered.uid
+    }
+
+    /// Get identity of the calling service, if any
+    pub fn service_id(&self) -> Option<&str> {
+        self.registered.service.as_ref().map(String::as_str)
+    }
+}
+
+impl<C> Deref for Token<C> {
+    type Target = RegisteredClaims;
+    fn deref(&self) -> &Self::Target {
+        &self.registered
+    }
+}
+
+/// JWT split into its three dot-separated segments
+struct RawToken<'a> {
+    header: &'a str,
+    claims: &'a str,
+    signature: &'a str,
+}
+
+impl<'a> RawToken<'a> {
+    fn split(token: &'a str) -> Result<Self> {
+        let segments: Vec<&str> = token.split('.').collect();
+        if segments.len() != 3 {
+            bail!(ErrorKind::MalformedToken("expected three segments"));
+        }
+
+        Ok(RawToken {
+            header: segments[0],
+            claims: segments[1],
+            signature: segments[2],
+        })
+    }
+
+    fn header(&self) -> Result<Header> {
+        decode_json(self.header)
+    }
+
+    fn claims<T: DeserializeOwned>(&self) -> Result<T> {
+        decode_json(self.claims)
+    }
+
+    /// Signed data is the `header.claims` part of the token
+    fn signed_data(&self) -> String {
+        format!("{}.{}", self.header, self.claims)
+    }
+
+    fn verify_signature(&self, alg: Algorithm, key: &PKeyRef) -> Result<()> {
+        let signature = decode_segment(self.signature)?;
+
+        let valid = match alg {
+            Algorithm::RS256 => {
+                let mut verifier = Verifier::new(alg.digest(), key)?;
+                verifier.update(self.signed_data().as_bytes())?;
+
+                // OpenSSL reports a malformed signature as an error rather than a mismatch
+                verifier.verify(&signature).unwrap_or(false)
+            }
+            Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => {
+                let mut signer = Signer::new(alg.digest(), key)?;
+                signer.update(self.signed_data().as_bytes())?;
+                let expected = signer.sign_to_vec()?;
+
+                expected.len() == signature.len() && memcmp::eq(&expected, &signature)
+            }
+        };
+
+        if !valid {
+            bail!(ErrorKind::InvalidSignature);
+        }
+        Ok(())
+    }
+}
+
+/// Encode and sign a JWT Token with the claims
+///
+/// The key is a private key for RS256 and an HMAC key for HS256/384/512
+pub fn sign<T: Serialize>(alg: Algorithm, kid: Option<&str>, key: &PKeyRef, claims: &T) -> Result<String> {
+    let header = Header {
+        alg: alg.name().to_owned(),
+        kid: kid.map(str::to_owned),
+    };
+    let signed_data = format!("{}.{}", encode_json(&header)?, encode_json(claims)?);
+
+    let mut signer = Signer::new(alg.digest(), key)?;
+    signer.update(signed_data.as_bytes())?;
+    let signature = signer.sign_to_vec()?;
+
+    Ok(format!("{}.{}", signed_data, base64::encode_config(&signature, base64::URL_SAFE_NO_PAD)))
+}
+
+fn encode_json<T: Serialize>(value: &T) -> Result<String> {
+    let bytes = json::to_vec(value)?;
+    Ok(base64::encode_config(&bytes, base64::URL_SAFE_NO_PAD))
+}
+
+fn decode_segment(segment: &str) -> Result<Vec<u8>> {
+    base64::decode_config(segment, base64::URL_SAFE_NO_PAD)
+        .map_err(|_| ErrorKind::MalformedToken("invalid base64 encoding").into())
+}
+
+fn decode_json<T: DeserializeOwned>(segment: &str) -> Result<T> {
+    let bytes = decode_segment(segment)?;
+    json::from_slice(&bytes).map_err(|_| ErrorKind::MalformedToken("invalid json").into())
+}
+
+/// Current time in seconds since UNIX epoch
+pub fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::pkey::PKey;
+    use token::stub::{encode, keypair};
+
+    #[derive(Debug, Deserialize)]
+    struct CustomClaims {
+        roles: Vec<String>,
+    }
+
+    fn claims(iat: i64, exp: i64) -> String {
+        format!(
+            r#"{{"sub":"12345","email":"user@example.com","iat":{},"exp":{},
+                "aud":"circles","iss":"https://securetoken.google.com/circles",
+                "auth_time":{},"roles":["admin"]}}"#,
+            iat,
+            exp,
+            iat
+        )
+    }
+
+    fn service_claims(iat: i64, exp: i64) -> String {
+        format!(
+            r#"{{"sub":"billing","svc":"billing","iat":{},"exp":{},
+                "aud":"circles","iss":"billing"}}"#,
+            iat,
+            exp
+        )
+    }
+
+    fn decode(jwt: &str, scheme: Scheme, key: &PKeyRef) -> Result<Token> {
+        Token::decode(jwt, scheme, key, &ValidationPolicy::new())
+    }
+
+    #[test]
+    fn decode_valid() {
+        let key = keypair();
+        let now = unix_now();
+        let jwt = encode(Algorithm::RS256, &key, &claims(now - 10, now + 3600));
+
+        assert_eq!(Header::decode(&jwt).unwrap().kid, Some("test".to_owned()));
+
+        let policy = ValidationPolicy::firebase("circles");
+        let token = Token::<NoClaims>::decode(&jwt, Scheme::IdToken, &key, &policy).unwrap();
+        assert_eq!(token.user_id(), "12345");
+        assert_eq!(token.email, Some("user@example.com".to_owned()));
+        assert_eq!(token.aud, vec!["circles".to_owned()]);
+        assert_eq!(token.scheme, Scheme::IdToken);
+    }
+
+    #[test]
+    fn decode_custom_claims() {
+        let key = keypair();
+        let now = unix_now();
+        let jwt = encode(Algorithm::RS256, &key, &claims(now - 10, now + 3600));
+
+        let policy = ValidationPolicy::new();
+        let token = Token::<CustomClaims>::decode(&jwt, Scheme::IdToken, &key, &policy).unwrap();
+        assert_eq!(token.claims.roles, vec!["admin".to_owned()]);
+        assert_eq!(token.uid, "12345");
+    }
+
+    #[test]
+    fn decode_shared_secret() {
+        let now = unix_now();
+        for &alg in &[Algorithm::HS256, Algorithm::HS384, Algorithm::HS512] {
+            let key = PKey::hmac(b"secret").unwrap();
+            let jwt = encode(alg, &key, &service_claims(now - 10, now + 60));
+
+            let token = decode(&jwt, Scheme::SharedSecret, &key).unwrap();
+            assert_eq!(token.service_id(), Some("billing"));
+            assert_eq!(token.scheme, Scheme::SharedSecret);
+
+            let other = PKey::hmac(b"another secret").unwrap();
+            match *decode(&jwt, Scheme::SharedSecret, &other).unwrap_err().kind() {
+                ErrorKind::InvalidSignature => (),
+                ref e => panic!("unexpected error: {}", e),
+            }
+        }
+    }
+
+    #[test]
+    fn decode_wrong_scheme() {
+        let now = unix_now();
+        let key = PKey::hmac(b"secret").unwrap();
+        let jwt = encode(Algorithm::HS256, &key, &service_claims(now - 10, now + 60));
+
+        match *decode(&jwt, Scheme::IdToken, &keypair()).unwrap_err().kind() {
+            ErrorKind::UnsupportedAlgorithm(..) => (),
+            ref e => panic!("unexpected error: {}", e),
+        }
+    }
+
+    #[test]
+    fn decode_bad_signature() {
+        let now = unix_now();
+        let jwt = encode(Algorithm::RS256, &keypair(), &claims(now - 10, now + 3600));
+
+        match *decode(&jwt, Scheme::IdToken, &keypair()).unwrap_err().kind() {
+            ErrorKind::InvalidSignature => (),
+            ref e => panic!("unexpected error: {}", e),
+        }
+    }
+
+    #[test]
+    fn decode_expired() {
+        let key = keypair();
+        let now = unix_now();
+        let jwt = encode(Algorithm::RS256, &key, &claims(now - 7200, now - 3600));
+
+        match *decode(&jwt, Scheme::IdToken, &key).unwrap_err().kind() {
+            ErrorKind::TokenExpired => (),
+            ref e => panic!("unexpected error: {}", e),
+        }
     }
 
-    fn verify(&self) -> Result<()> {
-        /* todo: check token validity */
-        unimplemented!()
+    #[test]
+    fn decode_malformed() {
+        match *decode("not.a-token", Scheme::IdToken, &keypair()).unwrap_err().kind() {
+            ErrorKind::MalformedToken(..) => (),
+            ref e => panic!("unexpected error: {}", e),
+        }
     }
-}
\ No newline at end of file
+}
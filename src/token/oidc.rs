@@ -0,0 +1,131 @@
+//! OpenID Connect Discovery of token issuers
+
+use token::{Result, ErrorKind};
+use token::keyring::Keyring;
+
+use json;
+use reqwest;
+
+use std::io::Read;
+
+/// Path of the discovery document relative to the issuer URL
+pub const DISCOVERY_PATH: &str = "/.well-known/openid-configuration";
+
+/// OpenID Provider Metadata members we rely on
+#[derive(Clone, Debug, Deserialize)]
+pub struct ProviderMetadata {
+    /// Issuer identifier: the `iss` claim of the provider tokens
+    pub issuer: String,
+    /// URL of the JWKS document with the provider signing keys
+    pub jwks_uri: String,
+    /// RFC 7662 introspection endpoint, if the provider has one
+    #[serde(default)]
+    pub introspection_endpoint: Option<String>,
+}
+
+/// URL of the discovery document of the issuer
+pub fn discovery_url(issuer: &str) -> String {
+    format!("{}{}", issuer.trim_right_matches('/'), DISCOVERY_PATH)
+}
+
+/// Fetch the discovery document of the issuer
+///
+/// The document must name the same issuer it has been requested for
+pub fn discover(issuer: &str) -> Result<ProviderMetadata> {
+    let url = discovery_url(issuer);
+    let mut response = reqwest::get(&url)?;
+    if !response.status().is_success() {
+        bail!(ErrorKind::FailedToDiscover(format!("{} responded {}", url, response.status())));
+    }
+
+    let mut body = String::new();
+    response.read_to_string(&mut body)?;
+    let metadata: ProviderMetadata = json::from_str(&body).map_err(|e| {
+        ErrorKind::FailedToDiscover(format!("invalid discovery document: {}", e))
+    })?;
+
+    if metadata.issuer != issuer {
+        bail!(ErrorKind::FailedToDiscover(format!(
+            "discovery document of {} names another issuer: {}",
+            issuer,
+            metadata.issuer
+        )));
+    }
+    Ok(metadata)
+}
+
+/// Retrieve the self-refreshing Keyring of the issuer
+pub fn keyring(issuer: &str) -> Result<Keyring> {
+    let metadata = discover(issuer)?;
+    info!("discovered {} keys at {}", issuer, metadata.jwks_uri);
+    Keyring::new(metadata.jwks_uri)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use token::{Algorithm, NoClaims, Scheme, StaticKeys, TokenVerifier, ValidationPolicy};
+    use token::jwk::{Jwk, JwkSet};
+    use token::stub::{encode, firebase_claims, keypair, StubServer};
+    use token::unix_now;
+    use openssl::pkey::PKey;
+
+    fn claims(issuer: &str) -> String {
+        let now = unix_now();
+        format!(
+            r#"{{"sub":"employee","iat":{},"exp":{},"aud":"circles","iss":"{}"}}"#,
+            now - 10,
+            now + 3600,
+            issuer
+        )
+    }
+
+    #[test]
+    fn verify_tokens_of_several_issuers() {
+        let enterprise_key = keypair();
+        let jwks = JwkSet { keys: vec![Jwk::from_key("test", &enterprise_key).unwrap()] };
+        let jwks_server = StubServer::serve(json::to_string(&jwks).unwrap(), vec![]);
+
+        let discovery_server = StubServer::serve(String::new(), vec![]);
+        let issuer = discovery_server.url().trim_right_matches('/').to_owned();
+        discovery_server.set_body(format!(
+            r#"{{"issuer":"{}","jwks_uri":"{}"}}"#,
+            issuer,
+            jwks_server.url()
+        ));
+
+        let firebase_key = keypair();
+        let firebase_public = PKey::public_key_from_der(&firebase_key.public_key_to_der().unwrap())
+            .unwrap();
+        let verifier = TokenVerifier::new(
+            StaticKeys::single(firebase_public),
+            ValidationPolicy::firebase("circles"),
+        ).with_oidc_issuer(&issuer, ValidationPolicy::new().audience("circles"))
+            .unwrap();
+
+        let jwt = encode(Algorithm::RS256, &enterprise_key, &claims(&issuer));
+        let token = verifier.verify_token::<_, NoClaims>(jwt).unwrap();
+        assert_eq!(token.user_id(), format!("{}|employee", issuer));
+        assert_eq!(token.scheme, Scheme::IdToken);
+
+        let jwt = encode(Algorithm::RS256, &firebase_key, &firebase_claims("12345"));
+        assert!(verifier.verify_token::<_, NoClaims>(jwt).is_ok());
+
+        // Keys of one issuer don't verify tokens of another
+        let jwt = encode(Algorithm::RS256, &firebase_key, &claims(&issuer));
+        assert!(verifier.verify_token::<_, NoClaims>(jwt).is_err());
+    }
+
+    #[test]
+    fn issuer_mismatch() {
+        let server = StubServer::serve(
+            r#"{"issuer":"https://elsewhere.example.com","jwks_uri":"https://elsewhere.example.com/jwks"}"#
+                .to_owned(),
+            vec![],
+        );
+        match *discover(server.url()).unwrap_err().kind() {
+            ErrorKind::FailedToDiscover(..) => (),
+            ref e => panic!("unexpected error: {}", e),
+        }
+    }
+}
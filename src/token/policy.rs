@@ -0,0 +1,222 @@
+//! Claims validation policy
+
+use token::{Result, ErrorKind};
+use token::RegisteredClaims;
+
+use json;
+
+use std::time::Duration;
+
+/// Rules the claims of a correctly signed token have to satisfy
+///
+/// # Examples
+///
+/// ```
+/// use std::time::Duration;
+/// use service::token::ValidationPolicy;
+///
+/// let policy = ValidationPolicy::firebase("circles")
+///     .leeway(Duration::from_secs(30))
+///     .max_age(Duration::from_secs(7 * 24 * 60 * 60))
+///     .require("email");
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct ValidationPolicy {
+    audience: Option<String>,
+    issuer: Option<String>,
+    leeway: i64,
+    max_age: Option<i64>,
+    required_claims: Vec<String>,
+}
+
+impl ValidationPolicy {
+    /// Policy checking token lifetime only
+    pub fn new() -> Self {
+        ValidationPolicy::default()
+    }
+
+    /// Policy for Firebase ID tokens issued for the project
+    pub fn firebase(project_id: &str) -> Self {
+        Self::new()
+            .audience(project_id)
+            .issuer(format!("https://securetoken.google.com/{}", project_id))
+            .require("auth_time")
+    }
+
+    /// Policy for service-to-service tokens addressed to the `audience` service
+    pub fn service(audience: &str) -> Self {
+        Self::new().audience(audience).require("svc")
+    }
+
+    /// Expected `aud` claim
+    pub fn audience<S: Into<String>>(mut self, audience: S) -> Self {
+        self.audience = Some(audience.into());
+        self
+    }
+
+    /// Expected `iss` claim
+    pub fn issuer<S: Into<String>>(mut self, issuer: S) -> Self {
+        self.issuer = Some(issuer.into());
+        self
+    }
+
+    /// Allowed clock skew for `exp`, `iat`, `nbf` and `auth_time` checks
+    pub fn leeway(mut self, leeway: Duration) -> Self {
+        self.leeway = leeway.as_secs() as i64;
+        self
+    }
+
+    /// Maximal time passed since the user has authenticated (`auth_time` claim)
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age.as_secs() as i64);
+        self
+    }
+
+    /// Claim that must be present in the token
+    pub fn require<S: Into<String>>(mut self, claim: S) -> Self {
+        self.required_claims.push(claim.into());
+        self
+    }
+
+    /// Check presence of required claims in the raw claims object
+    pub fn validate_claims(&self, claims: &json::Value) -> Result<()> {
+        for claim in &self.required_claims {
+            if claims.get(claim).map_or(true, json::Value::is_null) {
+                bail!(ErrorKind::MissingClaim(claim.clone()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Validate registered claims at the `now` moment (seconds since UNIX epoch)
+    pub fn validate(&self, claims: &RegisteredClaims, now: i64) -> Result<()> {
+        if claims.exp + self.leeway <= now {
+            bail!(ErrorKind::TokenExpired);
+        }
+        self.validate_unexpiring(claims, now)
+    }
+
+    /// Validate registered claims except `exp`, for tokens without an expiration time
+    pub fn validate_unexpiring(&self, claims: &RegisteredClaims, now: i64) -> Result<()> {
+        if claims.uid.is_empty() {
+            bail!(ErrorKind::EmptyUserID);
+        }
+
+        if claims.iat > now + self.leeway {
+            bail!(ErrorKind::TokenNotYetValid);
+        }
+        if claims.nbf.map_or(false, |nbf| nbf > now + self.leeway) {
+            bail!(ErrorKind::TokenNotYetValid);
+        }
+
+        if let Some(ref audience) = self.audience {
+            if !claims.aud.contains(audience) {
+                bail!(ErrorKind::InvalidAudience(claims.aud.join(", ")));
+            }
+        }
+        if let Some(ref issuer) = self.issuer {
+            if &claims.iss != issuer {
+                bail!(ErrorKind::InvalidIssuer(claims.iss.clone()));
+            }
+        }
+
+        if let Some(auth_time) = claims.auth_time {
+            if auth_time > now + self.leeway {
+                bail!(ErrorKind::TokenNotYetValid);
+            }
+        }
+        if let Some(max_age) = self.max_age {
+            let auth_time = match claims.auth_time {
+                Some(auth_time) => auth_time,
+                None => bail!(ErrorKind::MissingClaim("auth_time".to_owned())),
+            };
+            if now - auth_time > max_age + self.leeway {
+                bail!(ErrorKind::AuthenticationTooOld);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: i64 = 1_500_000_000;
+
+    fn token(claims: &str) -> RegisteredClaims {
+        json::from_str(claims).unwrap()
+    }
+
+    fn firebase_token(iat: i64, exp: i64) -> RegisteredClaims {
+        token(&format!(
+            r#"{{"sub":"12345","iat":{},"exp":{},"aud":"circles",
+                "iss":"https://securetoken.google.com/circles","auth_time":{}}}"#,
+            iat,
+            exp,
+            iat
+        ))
+    }
+
+    fn assert_fails(result: Result<()>, expected: &str) {
+        match result {
+            Ok(()) => panic!("expected {} error", expected),
+            Err(e) => assert!(
+                format!("{:?}", e.kind()).starts_with(expected),
+                "unexpected error {:?}",
+                e.kind()
+            ),
+        }
+    }
+
+    #[test]
+    fn firebase_policy() {
+        let policy = ValidationPolicy::firebase("circles");
+        assert!(policy.validate(&firebase_token(NOW - 10, NOW + 10), NOW).is_ok());
+
+        let other = ValidationPolicy::firebase("squares");
+        assert_fails(other.validate(&firebase_token(NOW - 10, NOW + 10), NOW), "InvalidAudience");
+
+        // Enterprise identity providers often send several audiences
+        let audiences = token(&format!(
+            r#"{{"sub":"12345","iat":{},"exp":{},"aud":["portal","circles"],"iss":"idp"}}"#,
+            NOW - 10,
+            NOW + 10
+        ));
+        assert!(policy.validate(&audiences, NOW).is_ok());
+        assert_fails(other.validate(&audiences, NOW), "InvalidAudience");
+
+        let issuer = ValidationPolicy::new().issuer("https://accounts.google.com");
+        assert_fails(issuer.validate(&firebase_token(NOW - 10, NOW + 10), NOW), "InvalidIssuer");
+    }
+
+    #[test]
+    fn leeway() {
+        let expired = firebase_token(NOW - 100, NOW - 5);
+        assert_fails(ValidationPolicy::new().validate(&expired, NOW), "TokenExpired");
+
+        let policy = ValidationPolicy::new().leeway(Duration::from_secs(10));
+        assert!(policy.validate(&expired, NOW).is_ok());
+        assert!(policy.validate(&firebase_token(NOW + 5, NOW + 100), NOW).is_ok());
+        assert_fails(policy.validate(&firebase_token(NOW + 50, NOW + 100), NOW), "TokenNotYetValid");
+    }
+
+    #[test]
+    fn max_age() {
+        let policy = ValidationPolicy::new().max_age(Duration::from_secs(60));
+        assert!(policy.validate(&firebase_token(NOW - 30, NOW + 10), NOW).is_ok());
+        assert_fails(policy.validate(&firebase_token(NOW - 90, NOW + 10), NOW), "AuthenticationTooOld");
+
+        let no_auth_time = token(r#"{"sub":"1","iat":0,"exp":2000000000,"aud":"a","iss":"b"}"#);
+        assert_fails(policy.validate(&no_auth_time, NOW), "MissingClaim");
+    }
+
+    #[test]
+    fn required_claims() {
+        let policy = ValidationPolicy::new().require("email");
+        assert!(policy.validate_claims(&json::from_str(r#"{"email":"a@b.c"}"#).unwrap()).is_ok());
+        assert_fails(policy.validate_claims(&json::from_str(r#"{"email":null}"#).unwrap()), "MissingClaim");
+        assert_fails(policy.validate_claims(&json::from_str("{}").unwrap()), "MissingClaim");
+    }
+}
@@ -0,0 +1,233 @@
+//! Token revocation list backed by PostgreSQL
+//!
+//! Revocations are stored in the `revoked_tokens` and `revoked_users` tables
+//! and announced with `NOTIFY` so that every instance of the service
+//! reloads its in-process copy of the list
+
+use token::{Result, ErrorKind};
+use token::RegisteredClaims;
+use token::cache::{self, Digest};
+
+use db;
+use db::{AsyncPgPool, SyncPgPool};
+use db::models::{RevokedToken, RevokedUser};
+use db::query::{Insert, REVOCATIONS_CHANNEL};
+
+use chrono::{NaiveDateTime, Utc};
+use fallible_iterator::FallibleIterator;
+use futures_cpupool::CpuFuture;
+use postgres::Connection;
+
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock, Weak};
+use std::thread;
+use std::time::Duration;
+
+/// Delay before listening again after the connection has failed
+const RECONNECT_DELAY_SECS: u64 = 5;
+
+/// In-process copy of the revocation tables kept up to date with `LISTEN`
+#[derive(Clone)]
+pub struct RevocationList {
+    revoked: Arc<RwLock<Revoked>>,
+}
+
+#[derive(Default)]
+struct Revoked {
+    /// Revoked token digests with their expiration times
+    tokens: HashMap<Digest, i64>,
+    /// Users with the moments their tokens were revoked at
+    users: HashMap<String, i64>,
+}
+
+impl RevocationList {
+    /// Load the revocation list and listen for changes in a background thread
+    pub fn listen(pool: &AsyncPgPool) -> db::Result<Self> {
+        let pool = pool.sync_pool().clone();
+        let revoked = load(&pool.get()?)?;
+        info!(
+            "loaded {} revoked tokens and {} revoked users",
+            revoked.tokens.len(),
+            revoked.users.len()
+        );
+
+        let revoked = Arc::new(RwLock::new(revoked));
+        spawn_listener(pool, Arc::downgrade(&revoked));
+
+        Ok(RevocationList { revoked })
+    }
+
+    /// Check that neither the token nor all tokens of its user are revoked
+    ///
+    /// Tokens are revoked by the time the user has logged in at,
+    /// so that tokens refreshed since then stay revoked too
+    pub fn check(&self, digest: &Digest, claims: &RegisteredClaims) -> Result<()> {
+        if self.revoked.read().unwrap().tokens.contains_key(digest) {
+            bail!(ErrorKind::TokenRevoked);
+        }
+        self.check_user(&claims.uid, claims.auth_time.unwrap_or(claims.iat))
+    }
+
+    /// Check that the user hasn't been revoked since logging in at `auth_time`
+    pub fn check_user(&self, uid: &str, auth_time: i64) -> Result<()> {
+        if let Some(&revoked_at) = self.revoked.read().unwrap().users.get(uid) {
+            if auth_time <= revoked_at {
+                bail!(ErrorKind::UserRevoked);
+            }
+        }
+        Ok(())
+    }
+
+    /// Revoke a single token until it expires at `exp`
+    pub fn revoke_token(&self, pool: &AsyncPgPool, token: &str, exp: i64) -> CpuFuture<u64, db::Error> {
+        let digest = cache::digest(token);
+        self.revoked.write().unwrap().tokens.insert(digest, exp);
+
+        RevokedToken {
+            digest: digest.to_vec(),
+            expires_at: NaiveDateTime::from_timestamp(exp, 0),
+        }.insert(pool)
+    }
+
+    /// Revoke all tokens issued to the user so far and terminate the user's sessions
+    pub fn revoke_user(&self, pool: &AsyncPgPool, uid: &str) -> CpuFuture<u64, db::Error> {
+        let revoked_at = Utc::now().naive_utc();
+        self.revoked
+            .write()
+            .unwrap()
+            .users
+            .insert(uid.to_owned(), revoked_at.timestamp());
+
+        RevokedUser {
+            uid: uid.to_owned(),
+            revoked_at,
+        }.insert(pool)
+    }
+}
+
+#[cfg(test)]
+impl RevocationList {
+    /// List of users revoked at the given moments, without a database
+    pub fn with_revoked_users(users: Vec<(&str, i64)>) -> Self {
+        let mut revoked = Revoked::default();
+        for (uid, revoked_at) in users {
+            revoked.users.insert(uid.to_owned(), revoked_at);
+        }
+        RevocationList { revoked: Arc::new(RwLock::new(revoked)) }
+    }
+}
+
+fn spawn_listener(pool: SyncPgPool, revoked: Weak<RwLock<Revoked>>) {
+    thread::spawn(move || loop {
+        match listen(&pool, &revoked) {
+            Ok(()) => break,
+            Err(e) => warn!("revocation list listener failed: {}", e),
+        }
+        thread::sleep(Duration::from_secs(RECONNECT_DELAY_SECS));
+    });
+}
+
+/// Reload the list on every notification until it is dropped or the connection fails
+fn listen(pool: &SyncPgPool, revoked: &Weak<RwLock<Revoked>>) -> db::Result<()> {
+    let conn = pool.get()?;
+    conn.execute(&format!("LISTEN {}", REVOCATIONS_CHANNEL), &[])?;
+
+    // Catch up with revocations missed while (re)connecting
+    if !reload(&conn, revoked)? {
+        return Ok(());
+    }
+
+    let notifications = conn.notifications();
+    let mut notifications = notifications.blocking_iter();
+    while let Some(_) = notifications.next()? {
+        if !reload(&conn, revoked)? {
+            return Ok(());
+        }
+    }
+    Ok(())
+}
+
+/// Replace the list with the tables contents. Returns false if the list has been dropped
+fn reload(conn: &Connection, revoked: &Weak<RwLock<Revoked>>) -> db::Result<bool> {
+    let loaded = load(conn)?;
+    match revoked.upgrade() {
+        Some(revoked) => {
+            debug!("reloaded revocation list");
+            *revoked.write().unwrap() = loaded;
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+fn load(conn: &Connection) -> db::Result<Revoked> {
+    let mut revoked = Revoked::default();
+
+    let tokens = conn.query(
+        "SELECT digest, expires_at FROM revoked_tokens
+         WHERE expires_at > (now() AT TIME ZONE 'utc')",
+        &[],
+    )?;
+    for row in &tokens {
+        let digest: Vec<u8> = row.get(0);
+        let expires_at: NaiveDateTime = row.get(1);
+        if digest.len() == 32 {
+            let mut key = [0u8; 32];
+            key.copy_from_slice(&digest);
+            revoked.tokens.insert(key, expires_at.timestamp());
+        }
+    }
+
+    let users = conn.query("SELECT uid, revoked_at FROM revoked_users", &[])?;
+    for row in &users {
+        let uid: String = row.get(0);
+        let revoked_at: NaiveDateTime = row.get(1);
+        revoked.users.insert(uid, revoked_at.timestamp());
+    }
+
+    Ok(revoked)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use json;
+
+    fn claims(uid: &str, iat: i64) -> RegisteredClaims {
+        json::from_str(&format!(
+            r#"{{"sub":"{}","iat":{},"exp":1000,"aud":"a","iss":"b"}}"#,
+            uid, iat
+        )).unwrap()
+    }
+
+    fn session_claims(uid: &str, iat: i64, auth_time: i64) -> RegisteredClaims {
+        json::from_str(&format!(
+            r#"{{"sub":"{}","iat":{},"exp":1000,"aud":"a","iss":"b","auth_time":{}}}"#,
+            uid, iat, auth_time
+        )).unwrap()
+    }
+
+    #[test]
+    fn check() {
+        let mut revoked = Revoked::default();
+        revoked.tokens.insert(cache::digest("revoked"), 1000);
+        revoked.users.insert("1".to_owned(), 100);
+        let list = RevocationList { revoked: Arc::new(RwLock::new(revoked)) };
+
+        assert!(list.check(&cache::digest("valid"), &claims("2", 0)).is_ok());
+        match *list.check(&cache::digest("revoked"), &claims("2", 0)).unwrap_err().kind() {
+            ErrorKind::TokenRevoked => (),
+            ref e => panic!("unexpected error: {}", e),
+        }
+        match *list.check(&cache::digest("valid"), &claims("1", 100)).unwrap_err().kind() {
+            ErrorKind::UserRevoked => (),
+            ref e => panic!("unexpected error: {}", e),
+        }
+        // Tokens issued after the user has been revoked are accepted
+        assert!(list.check(&cache::digest("valid"), &claims("1", 101)).is_ok());
+
+        // Refreshed after revocation, but logged in before it
+        assert!(list.check(&cache::digest("valid"), &session_claims("1", 200, 50)).is_err());
+        assert!(list.check(&cache::digest("valid"), &session_claims("1", 200, 150)).is_ok());
+    }
+}
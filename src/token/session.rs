@@ -0,0 +1,421 @@
+//! Session access and refresh tokens issued by this service
+//!
+//! After login with a Firebase ID token users get a short-lived access token,
+//! a JWT signed with our own key pair, and a long-lived opaque refresh token.
+//! Refresh tokens are rotated on every use: presenting a used one again
+//! means it has leaked, so the whole session is terminated
+
+use token::{Algorithm, Key, Result, Error, ErrorKind};
+use token::{StaticKeys, ValidationPolicy};
+use token::jwk::{Jwk, JwkSet};
+use token::{cache, sign, unix_now};
+use token::revocation::RevocationList;
+
+use db;
+use db::AsyncPgPool;
+use db::models::RefreshToken;
+use db::query::Insert;
+
+use base64;
+use chrono::NaiveDateTime;
+use futures::Future;
+use futures::future;
+use openssl::pkey::PKey;
+use openssl::rand::rand_bytes;
+use openssl::sha::sha256;
+use postgres::Connection;
+
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Default access token lifetime: 15 minutes
+const DEFAULT_ACCESS_TTL_SECS: i64 = 15 * 60;
+/// Default refresh token lifetime: 30 days
+const DEFAULT_REFRESH_TTL_SECS: i64 = 30 * 24 * 60 * 60;
+
+/// Future session produced by login or refresh
+pub type FutureSession = Box<Future<Item = Session, Error = Error>>;
+
+/// Issuer of session tokens signed with a local private key
+///
+/// Access tokens are verified by a `TokenVerifier`
+/// configured with `with_session_keys` and authenticated as `Scheme::Session`.
+/// Retired keys are only used for verification, so that keys can be rotated
+/// without invalidating access tokens already issued
+#[derive(Clone)]
+pub struct SessionIssuer {
+    issuer: String,
+    audience: String,
+    kid: String,
+    key: Arc<Key>,
+    retired: Vec<(String, Arc<Key>)>,
+    access_ttl: i64,
+    refresh_ttl: i64,
+    revocations: Option<RevocationList>,
+}
+
+/// Tokens returned to a client after login or refresh
+#[derive(Debug, Clone, Serialize)]
+pub struct Session {
+    /// Access token to send as `Authorization: Bearer`
+    pub access_token: String,
+    /// Always "Bearer"
+    pub token_type: &'static str,
+    /// Access token lifetime in seconds
+    pub expires_in: i64,
+    /// Single-use token to exchange for a new session at `/token/refresh`
+    pub refresh_token: String,
+}
+
+#[derive(Serialize)]
+struct AccessClaims<'a> {
+    sub: &'a str,
+    iat: i64,
+    exp: i64,
+    aud: &'a str,
+    iss: &'a str,
+    auth_time: i64,
+}
+
+/// Result of a refresh token lookup
+enum Exchange {
+    Rotated { uid: String, auth_time: i64 },
+    Unknown,
+    Reused,
+}
+
+impl SessionIssuer {
+    /// Construct a SessionIssuer signing tokens for the `audience` with an RSA private key
+    pub fn new<I, A>(issuer: I, audience: A, key: Key) -> Result<Self>
+    where
+        I: Into<String>,
+        A: Into<String>,
+    {
+        Ok(SessionIssuer {
+            issuer: issuer.into(),
+            audience: audience.into(),
+            kid: key_id(&key)?,
+            key: Arc::new(key),
+            retired: Vec::new(),
+            access_ttl: DEFAULT_ACCESS_TTL_SECS,
+            refresh_ttl: DEFAULT_REFRESH_TTL_SECS,
+            revocations: None,
+        })
+    }
+
+    /// Load a PEM-encoded RSA private key from a file
+    pub fn from_file<I, A, P>(issuer: I, audience: A, path: P) -> Result<Self>
+    where
+        I: Into<String>,
+        A: Into<String>,
+        P: AsRef<Path>,
+    {
+        Self::new(issuer, audience, load_key(path)?)
+    }
+
+    /// Keep accepting access tokens signed with a previous key
+    pub fn with_retired_key(mut self, key: Key) -> Result<Self> {
+        let public = PKey::public_key_from_der(&key.public_key_to_der()?)?;
+        self.retired.push((key_id(&public)?, Arc::new(public)));
+        Ok(self)
+    }
+
+    /// Keep accepting access tokens signed with a previous key loaded from a PEM file
+    pub fn with_retired_key_file<P: AsRef<Path>>(self, path: P) -> Result<Self> {
+        self.with_retired_key(load_key(path)?)
+    }
+
+    /// Access token lifetime
+    pub fn access_ttl(mut self, ttl: Duration) -> Self {
+        self.access_ttl = ttl.as_secs() as i64;
+        self
+    }
+
+    /// Refresh token lifetime, counted from the last refresh
+    pub fn refresh_ttl(mut self, ttl: Duration) -> Self {
+        self.refresh_ttl = ttl.as_secs() as i64;
+        self
+    }
+
+    /// Refuse to refresh sessions of revoked users
+    pub fn with_revocation_list(mut self, revocations: RevocationList) -> Self {
+        self.revocations = Some(revocations);
+        self
+    }
+
+    /// `iss` claim of the access tokens
+    pub fn issuer(&self) -> &str {
+        &self.issuer
+    }
+
+    /// ID of the signing key, derived from its public half
+    pub fn key_id(&self) -> &str {
+        &self.kid
+    }
+
+    /// Public keys to verify access tokens with: the current and the retired ones
+    pub fn verification_keys(&self) -> Result<StaticKeys> {
+        let public = PKey::public_key_from_der(&self.key.public_key_to_der()?)?;
+        let mut keys = StaticKeys::new().with_key(self.kid.clone(), public);
+        for &(ref kid, ref key) in &self.retired {
+            keys = keys.with_key(kid.clone(), PKey::public_key_from_der(&key.public_key_to_der()?)?);
+        }
+        Ok(keys)
+    }
+
+    /// Public keys to publish for other services, the current key first
+    pub fn jwks(&self) -> Result<JwkSet> {
+        let mut keys = vec![Jwk::from_key(self.kid.clone(), &self.key)?];
+        for &(ref kid, ref key) in &self.retired {
+            keys.push(Jwk::from_key(kid.clone(), key)?);
+        }
+        Ok(JwkSet { keys })
+    }
+
+    /// Policy access tokens are validated with
+    pub fn policy(&self) -> ValidationPolicy {
+        ValidationPolicy::new()
+            .audience(self.audience.clone())
+            .issuer(self.issuer.clone())
+            .require("auth_time")
+    }
+
+    /// Start a new session for the user who has logged in at `auth_time`
+    pub fn start(&self, pool: &AsyncPgPool, uid: &str, auth_time: i64) -> FutureSession {
+        let issued = random_bytes().and_then(|family| {
+            let (refresh_token, stored) = self.refresh_token(uid, auth_time, family.to_vec())?;
+            let session = self.session(uid, auth_time, refresh_token)?;
+            Ok((session, stored))
+        });
+
+        match issued {
+            Ok((session, stored)) => box stored.insert(pool).from_err().map(move |_| session),
+            Err(e) => box future::err(e),
+        }
+    }
+
+    /// Exchange a refresh token for a new session
+    ///
+    /// The refresh token is marked as used; if it has been used already,
+    /// all refresh tokens of its session are deleted and `RefreshTokenReused` is returned
+    pub fn refresh(&self, pool: &AsyncPgPool, refresh_token: &str) -> FutureSession {
+        let digest = cache::digest(refresh_token).to_vec();
+        let successor = match random_token() {
+            Ok(successor) => successor,
+            Err(e) => return box future::err(e),
+        };
+        let expires_at = NaiveDateTime::from_timestamp(unix_now() + self.refresh_ttl, 0);
+        let successor_digest = cache::digest(&successor).to_vec();
+
+        let exchanged = pool.request(move |conn| {
+            future::result(exchange(&conn, &digest, &successor_digest, expires_at))
+        });
+
+        let issuer = self.clone();
+        box exchanged.from_err().and_then(move |exchange| match exchange {
+            Exchange::Rotated { uid, auth_time } => issuer.rotated(&uid, auth_time, successor),
+            Exchange::Unknown => bail!(ErrorKind::InvalidRefreshToken),
+            Exchange::Reused => {
+                warn!("refresh token reuse detected, session terminated");
+                bail!(ErrorKind::RefreshTokenReused)
+            }
+        })
+    }
+
+    /// Session continuing after its refresh token has been rotated, unless the user is revoked
+    fn rotated(&self, uid: &str, auth_time: i64, refresh_token: String) -> Result<Session> {
+        if let Some(ref revocations) = self.revocations {
+            revocations.check_user(uid, auth_time)?;
+        }
+        self.session(uid, auth_time, refresh_token)
+    }
+
+    fn session(&self, uid: &str, auth_time: i64, refresh_token: String) -> Result<Session> {
+        Ok(Session {
+            access_token: self.access_token(uid, auth_time)?,
+            token_type: "Bearer",
+            expires_in: self.access_ttl,
+            refresh_token,
+        })
+    }
+
+    fn access_token(&self, uid: &str, auth_time: i64) -> Result<String> {
+        let now = unix_now();
+        let claims = AccessClaims {
+            sub: uid,
+            iat: now,
+            exp: now + self.access_ttl,
+            aud: &self.audience,
+            iss: &self.issuer,
+            auth_time,
+        };
+        sign(Algorithm::RS256, Some(&self.kid), &self.key, &claims)
+    }
+
+    /// Generate a refresh token of a session and its database record
+    fn refresh_token(&self, uid: &str, auth_time: i64, family: Vec<u8>) -> Result<(String, RefreshToken)> {
+        let token = random_token()?;
+        let stored = RefreshToken {
+            digest: cache::digest(&token).to_vec(),
+            family,
+            uid: uid.to_owned(),
+            auth_time: NaiveDateTime::from_timestamp(auth_time, 0),
+            expires_at: NaiveDateTime::from_timestamp(unix_now() + self.refresh_ttl, 0),
+            used: false,
+        };
+        Ok((token, stored))
+    }
+}
+
+/// Mark the refresh token used and store its successor in one transaction
+fn exchange(
+    conn: &Connection,
+    digest: &[u8],
+    successor: &[u8],
+    expires_at: NaiveDateTime,
+) -> db::Result<Exchange> {
+    let transaction = conn.transaction()?;
+
+    let found = {
+        let rows = transaction.query(
+            "SELECT family, uid, auth_time, used FROM refresh_tokens
+             WHERE digest = $1 AND expires_at > (now() AT TIME ZONE 'utc')
+             FOR UPDATE",
+            &[&digest],
+        )?;
+        rows.iter().next().map(|row| {
+            let family: Vec<u8> = row.get(0);
+            let uid: String = row.get(1);
+            let auth_time: NaiveDateTime = row.get(2);
+            let used: bool = row.get(3);
+            (family, uid, auth_time, used)
+        })
+    };
+
+    let (family, uid, auth_time, used) = match found {
+        Some(found) => found,
+        None => return Ok(Exchange::Unknown),
+    };
+
+    if used {
+        transaction.execute("DELETE FROM refresh_tokens WHERE family = $1", &[&family])?;
+        transaction.commit()?;
+        return Ok(Exchange::Reused);
+    }
+
+    transaction.execute(
+        "UPDATE refresh_tokens SET used = TRUE WHERE digest = $1",
+        &[&digest],
+    )?;
+    transaction.execute(
+        "INSERT INTO refresh_tokens VALUES($1, $2, $3, $4, $5, FALSE)",
+        &[&successor, &family, &uid, &auth_time, &expires_at],
+    )?;
+    transaction.commit()?;
+
+    Ok(Exchange::Rotated {
+        uid,
+        auth_time: auth_time.timestamp(),
+    })
+}
+
+/// 256 random bits
+fn random_bytes() -> Result<[u8; 32]> {
+    let mut bytes = [0u8; 32];
+    rand_bytes(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// 256 random bits encoded with URL-safe base64
+fn random_token() -> Result<String> {
+    Ok(base64::encode_config(&random_bytes()?, base64::URL_SAFE_NO_PAD))
+}
+
+fn load_key<P: AsRef<Path>>(path: P) -> Result<Key> {
+    let mut pem = Vec::new();
+    File::open(path.as_ref())?.read_to_end(&mut pem)?;
+    Ok(PKey::private_key_from_pem(&pem)?)
+}
+
+/// Key ID: truncated SHA-256 of the DER-encoded public key
+fn key_id(key: &Key) -> Result<String> {
+    let digest = sha256(&key.public_key_to_der()?);
+    Ok(base64::encode_config(&digest[..12], base64::URL_SAFE_NO_PAD))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use token::{NoClaims, Scheme, TokenVerifier};
+    use token::stub::keypair;
+
+    #[test]
+    fn access_token_is_verified_as_session() {
+        let issuer = SessionIssuer::new("circles", "circles-api", keypair()).unwrap();
+        let access_token = issuer.access_token("12345", unix_now() - 60).unwrap();
+
+        let verifier = TokenVerifier::new(StaticKeys::new(), ValidationPolicy::new())
+            .with_session_keys(
+                issuer.issuer(),
+                issuer.verification_keys().unwrap(),
+                issuer.policy(),
+            );
+
+        let token = verifier.verify_token::<_, NoClaims>(access_token).unwrap();
+        assert_eq!(token.scheme, Scheme::Session);
+        assert_eq!(token.user_id(), "12345");
+        assert_eq!(token.aud, vec!["circles-api".to_owned()]);
+    }
+
+    #[test]
+    fn retired_keys_are_published() {
+        let retired = keypair();
+        let copy = PKey::private_key_from_pem(&retired.private_key_to_pem().unwrap()).unwrap();
+        let retired_token = SessionIssuer::new("circles", "circles-api", copy)
+            .unwrap()
+            .access_token("12345", unix_now())
+            .unwrap();
+
+        let issuer = SessionIssuer::new("circles", "circles-api", keypair())
+            .unwrap()
+            .with_retired_key(retired)
+            .unwrap();
+
+        let jwks = issuer.jwks().unwrap();
+        assert_eq!(jwks.keys.len(), 2);
+        assert_eq!(jwks.keys[0].kid.as_ref().map(String::as_str), Some(issuer.key_id()));
+        assert_eq!(jwks.to_keys().unwrap().len(), 2);
+
+        let verifier = TokenVerifier::new(StaticKeys::new(), ValidationPolicy::new())
+            .with_session_keys(issuer.issuer(), issuer.verification_keys().unwrap(), issuer.policy());
+        assert!(verifier.verify_token::<_, NoClaims>(retired_token).is_ok());
+    }
+
+    #[test]
+    fn revoked_users_cannot_refresh() {
+        let now = unix_now();
+        let revocations = RevocationList::with_revoked_users(vec![("12345", now - 60)]);
+        let issuer = SessionIssuer::new("circles", "circles-api", keypair())
+            .unwrap()
+            .with_revocation_list(revocations);
+
+        match *issuer.rotated("12345", now - 3600, "next".to_owned()).unwrap_err().kind() {
+            ErrorKind::UserRevoked => (),
+            ref e => panic!("unexpected error: {}", e),
+        }
+        // Logged in again after the revocation
+        assert!(issuer.rotated("12345", now, "next".to_owned()).is_ok());
+        assert!(issuer.rotated("67890", now - 3600, "next".to_owned()).is_ok());
+    }
+
+    #[test]
+    fn key_id_is_stable() {
+        let key = keypair();
+        let public = PKey::public_key_from_der(&key.public_key_to_der().unwrap()).unwrap();
+        assert_eq!(key_id(&key).unwrap(), key_id(&public).unwrap());
+        assert_eq!(key_id(&key).unwrap().len(), 16);
+    }
+}
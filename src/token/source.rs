@@ -0,0 +1,182 @@
+//! Sources of public keys to verify token signatures with
+
+use token::{Key, Result, ErrorKind};
+use token::jwk::JwkSet;
+use token::keyring::Keyring;
+
+use json;
+use openssl::pkey::PKey;
+use openssl::x509::X509;
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Public keys mapped by their key IDs
+pub type Keys = BTreeMap<String, Arc<Key>>;
+
+/// Source of token verification keys
+///
+/// Implementations must be thread safe: keys are looked up from the CpuPool
+pub trait KeySource: Send + Sync {
+    /// Get a key by the `kid` from the token header
+    fn key(&self, kid: Option<&str>) -> Result<Arc<Key>>;
+}
+
+impl<S: KeySource + ?Sized> KeySource for Box<S> {
+    fn key(&self, kid: Option<&str>) -> Result<Arc<Key>> {
+        (**self).key(kid)
+    }
+}
+
+impl KeySource for Keyring {
+    fn key(&self, kid: Option<&str>) -> Result<Arc<Key>> {
+        match kid {
+            Some(kid) => self.get(kid),
+            None => bail!(ErrorKind::MalformedToken("missing key id")),
+        }
+    }
+}
+
+/// Fixed set of keys: loaded from a file or constructed in memory
+#[derive(Default)]
+pub struct StaticKeys {
+    keys: Keys,
+    fallback: Option<Arc<Key>>,
+}
+
+impl StaticKeys {
+    /// Construct an empty set of keys
+    pub fn new() -> Self {
+        StaticKeys::default()
+    }
+
+    /// Set that verifies tokens with a single key, whatever their `kid` is
+    pub fn single(key: Key) -> Self {
+        StaticKeys {
+            keys: Keys::new(),
+            fallback: Some(Arc::new(key)),
+        }
+    }
+
+    /// Set of a single HMAC key for service tokens signed with a shared secret
+    pub fn shared_secret(secret: &[u8]) -> Result<Self> {
+        Ok(Self::single(PKey::hmac(secret)?))
+    }
+
+    /// Add a key with an ID to the set
+    pub fn with_key<K: Into<String>>(mut self, kid: K, key: Key) -> Self {
+        self.keys.insert(kid.into(), Arc::new(key));
+        self
+    }
+
+    /// Load keys from a file on disk
+    ///
+    /// The file is either a single PEM-encoded public key or x509 certificate,
+    /// or a JSON document in any format [`parse_keys`](fn.parse_keys.html) accepts
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let mut contents = String::new();
+        File::open(path.as_ref())?.read_to_string(&mut contents)?;
+
+        if contents.trim_left().starts_with("-----BEGIN") {
+            Ok(Self::single(parse_pem(&contents)?))
+        } else {
+            Ok(StaticKeys {
+                keys: parse_keys(&contents)?,
+                fallback: None,
+            })
+        }
+    }
+}
+
+impl KeySource for StaticKeys {
+    fn key(&self, kid: Option<&str>) -> Result<Arc<Key>> {
+        if let Some(key) = kid.and_then(|kid| self.keys.get(kid)) {
+            return Ok(key.clone());
+        }
+
+        match self.fallback {
+            Some(ref key) => Ok(key.clone()),
+            None => bail!(ErrorKind::UnknownKeyID),
+        }
+    }
+}
+
+/// Open a key source by its location
+///
+/// `http://` and `https://` URLs are served by a self-refreshing [`Keyring`](../keyring/struct.Keyring.html),
+/// anything else is treated as a path to a key file
+pub fn open(location: &str) -> Result<Box<KeySource>> {
+    if location.starts_with("http://") || location.starts_with("https://") {
+        Ok(Box::new(Keyring::new(location)?))
+    } else {
+        Ok(Box::new(StaticKeys::from_file(location)?))
+    }
+}
+
+/// Parse a JSON document with public keys
+///
+/// Both JWKS documents and `{ kid: x509 pem }` maps served by Google are accepted
+pub fn parse_keys(document: &str) -> Result<Keys> {
+    let document: json::Value = json::from_str(document)?;
+
+    if document.get("keys").is_some() {
+        let jwks: JwkSet = json::from_value(document)?;
+        return jwks.to_keys();
+    }
+
+    let certificates: BTreeMap<String, String> = json::from_value(document)?;
+    certificates
+        .into_iter()
+        .map(|(kid, pem)| parse_pem(&pem).map(|key| (kid, Arc::new(key))))
+        .collect()
+}
+
+/// Parse a PEM-encoded x509 certificate or a public key
+pub fn parse_pem(pem: &str) -> Result<Key> {
+    if pem.contains("-----BEGIN CERTIFICATE-----") {
+        let certificate = X509::from_pem(pem.as_bytes())?;
+        Ok(certificate.public_key()?)
+    } else {
+        Ok(PKey::public_key_from_pem(pem.as_bytes())?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use token::stub::{self, keypair, StubServer};
+
+    #[test]
+    fn static_keys() {
+        let keys = StaticKeys::new().with_key("a", keypair());
+
+        assert!(keys.key(Some("a")).is_ok());
+        assert!(keys.key(Some("b")).is_err());
+        assert!(keys.key(None).is_err());
+
+        let single = StaticKeys::single(keypair());
+        assert!(single.key(Some("b")).is_ok());
+        assert!(single.key(None).is_ok());
+    }
+
+    #[test]
+    fn parse_both_formats() {
+        let keys = parse_keys(&stub::certificates(&["a", "b"])).unwrap();
+        assert_eq!(keys.len(), 2);
+
+        let keys = parse_keys(&stub::jwks(&["c"])).unwrap();
+        assert!(keys.contains_key("c"));
+    }
+
+    #[test]
+    fn jwks_keyring() {
+        let server = StubServer::serve(stub::jwks(&["a"]), vec![]);
+        let source = open(server.url()).unwrap();
+
+        assert!(source.key(Some("a")).is_ok());
+        assert!(source.key(None).is_err());
+    }
+}
@@ -0,0 +1,150 @@
+//! Local stand-in for remote HTTP endpoints used in tests
+
+use futures::future::{ok, FutureResult};
+use hyper;
+use hyper::server::{Http, Request, Response, Service};
+use json;
+use openssl::hash::MessageDigest;
+use openssl::pkey::{PKey, PKeyRef};
+use openssl::rsa::Rsa;
+#[allow(deprecated)]
+use openssl::x509::X509Generator;
+use token::{sign, unix_now, Algorithm};
+use token::jwk::{Jwk, JwkSet};
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::channel;
+use std::thread;
+
+/// HTTP server answering every request with the same body and headers
+///
+/// Runs in its own thread for the rest of the test process lifetime
+pub struct StubServer {
+    url: String,
+    body: Arc<Mutex<String>>,
+    hits: Arc<AtomicUsize>,
+}
+
+impl StubServer {
+    /// Start serving `body` with raw `headers` on a random local port
+    pub fn serve(body: String, headers: Vec<(&'static str, String)>) -> Self {
+        let body = Arc::new(Mutex::new(body));
+        let hits = Arc::new(AtomicUsize::new(0));
+
+        let service = StubService {
+            body: body.clone(),
+            headers: Arc::new(headers),
+            hits: hits.clone(),
+        };
+
+        let (tx, rx) = channel();
+        thread::spawn(move || {
+            let addr = "127.0.0.1:0".parse().unwrap();
+            let server = Http::new()
+                .bind(&addr, move || Ok(service.clone()))
+                .unwrap();
+            tx.send(server.local_addr().unwrap()).unwrap();
+            server.run().unwrap()
+        });
+
+        StubServer {
+            url: format!("http://{}/", rx.recv().unwrap()),
+            body,
+            hits,
+        }
+    }
+
+    /// URL the server listens on
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Replace the body served from now on
+    pub fn set_body(&self, body: String) {
+        *self.body.lock().unwrap() = body;
+    }
+
+    /// Number of requests served so far
+    pub fn hits(&self) -> usize {
+        self.hits.load(Ordering::SeqCst)
+    }
+}
+
+#[derive(Clone)]
+struct StubService {
+    body: Arc<Mutex<String>>,
+    headers: Arc<Vec<(&'static str, String)>>,
+    hits: Arc<AtomicUsize>,
+}
+
+impl Service for StubService {
+    type Request = Request;
+    type Response = Response;
+    type Error = hyper::Error;
+    type Future = FutureResult<Response, hyper::Error>;
+
+    fn call(&self, _req: Request) -> Self::Future {
+        self.hits.fetch_add(1, Ordering::SeqCst);
+
+        let mut response = Response::new();
+        for &(name, ref value) in self.headers.iter() {
+            response.headers_mut().set_raw(name, value.clone());
+        }
+        response.set_body(self.body.lock().unwrap().clone());
+        ok(response)
+    }
+}
+
+/// Generate a fresh RSA key pair
+pub fn keypair() -> PKey {
+    PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap()
+}
+
+/// JSON map of freshly generated self-signed x509 certificates, the way Google serves them
+#[allow(deprecated)]
+pub fn certificates(kids: &[&str]) -> String {
+    let certificates: BTreeMap<&str, String> = kids.iter()
+        .map(|kid| {
+            let certificate = X509Generator::new()
+                .set_sign_hash(MessageDigest::sha256())
+                .sign(&keypair())
+                .unwrap();
+            let pem = String::from_utf8(certificate.to_pem().unwrap()).unwrap();
+            (*kid, pem)
+        })
+        .collect();
+
+    json::to_string(&certificates).unwrap()
+}
+
+/// JWKS document of freshly generated RSA keys
+pub fn jwks(kids: &[&str]) -> String {
+    let jwks = JwkSet {
+        keys: kids.iter()
+            .map(|kid| Jwk::from_key(*kid, &keypair()).unwrap())
+            .collect(),
+    };
+
+    json::to_string(&jwks).unwrap()
+}
+
+/// Sign the JSON `claims` into a JWT with the `test` key ID
+pub fn encode(alg: Algorithm, key: &PKeyRef, claims: &str) -> String {
+    let claims: json::Value = json::from_str(claims).unwrap();
+    sign(alg, Some("test"), key, &claims).unwrap()
+}
+
+/// Claims of a Firebase ID token for the `circles` project valid for the next hour
+pub fn firebase_claims(uid: &str) -> String {
+    let now = unix_now();
+    format!(
+        r#"{{"sub":"{}","iat":{},"exp":{},"aud":"circles",
+            "iss":"https://securetoken.google.com/circles","auth_time":{}}}"#,
+        uid,
+        now - 10,
+        now + 3600,
+        now - 10
+    )
+}
@@ -1,67 +1,406 @@
 
-//! Token Verifiers built with Google Keyring
+//! Token Verifiers built on top of a KeySource
 
 use token::{Result, Error, ErrorKind};
-use token::Token;
+use token::{Algorithm, Header, Scheme, Token, UnverifiedClaims};
+use token::policy::ValidationPolicy;
+use token::oidc;
+use token::source::KeySource;
 
-use json;
-use openssl::aes::AesKey;
-use reqwest;
-use reqwest::StatusCode;
+use openssl::pkey::PKey;
+use serde::de::DeserializeOwned;
 
 use std::borrow::Cow;
 use std::collections::BTreeMap;
-use std::io::Read;
-use std::sync::{Arc, RwLock};
-use std::thread;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::sync::Arc;
 
-pub type Key = AesKey;
+/// Public key to verify token signatures with
+pub type Key = PKey;
 
-/// TokenVerifier verifies tokens using the key
+/// TokenVerifier verifies tokens using keys from the KeySource
+/// and validates their claims according to the ValidationPolicy
+///
+/// Service tokens signed with HMAC are only accepted if shared secrets are provided.
+/// Tokens of additional issuers, including our own session tokens,
+/// are verified with the keys and policy of the issuer named in their `iss` claim
 pub struct TokenVerifier {
-    key: Key,
+    keys: Box<KeySource>,
+    policy: ValidationPolicy,
+    shared: Option<(Box<KeySource>, ValidationPolicy)>,
+    issuers: BTreeMap<String, Issuer>,
+}
+
+/// Additional issuer of tokens
+struct Issuer {
+    scheme: Scheme,
+    keys: Box<KeySource>,
+    policy: ValidationPolicy,
+    /// Whether user IDs of the issuer are prefixed with it to keep them apart from Firebase uids
+    namespaced: bool,
 }
 
 impl TokenVerifier {
     /// Constructs a TokenVerifier
-    pub fn new(key: Key) -> Self {
-        TokenVerifier { key }
+    pub fn new<S: KeySource + 'static>(keys: S, policy: ValidationPolicy) -> Self {
+        TokenVerifier {
+            keys: Box::new(keys),
+            policy,
+            shared: None,
+            issuers: BTreeMap::new(),
+        }
+    }
+
+    /// Accept HS256/384/512 service tokens signed with one of the shared secrets
+    pub fn with_shared_secrets<S>(mut self, secrets: S, policy: ValidationPolicy) -> Self
+    where
+        S: KeySource + 'static,
+    {
+        self.shared = Some((Box::new(secrets), policy));
+        self
+    }
+
+    /// Accept session access tokens of the `issuer`, see [`SessionIssuer`](../session/struct.SessionIssuer.html)
+    pub fn with_session_keys<I, S>(self, issuer: I, keys: S, policy: ValidationPolicy) -> Self
+    where
+        I: Into<String>,
+        S: KeySource + 'static,
+    {
+        self.with_scheme_issuer(Scheme::Session, issuer.into(), Box::new(keys), policy)
     }
 
-    /// Decode and verify a Token
-    pub fn verify_token<T>(&self, token: T) -> Result<Token>
+    /// Accept ID tokens of another issuer signed with its keys
+    ///
+    /// The policy is amended to require the `iss` claim to be the issuer.
+    /// Subjects of different issuers may clash, so user IDs of the issuer's tokens
+    /// are `<issuer>|<sub>`
+    pub fn with_issuer<I, S>(self, issuer: I, keys: S, policy: ValidationPolicy) -> Self
+    where
+        I: Into<String>,
+        S: KeySource + 'static,
+    {
+        self.with_scheme_issuer(Scheme::IdToken, issuer.into(), Box::new(keys), policy)
+    }
+
+    /// Accept ID tokens of an OpenID Connect provider found by its discovery document
+    pub fn with_oidc_issuer(self, issuer: &str, policy: ValidationPolicy) -> Result<Self> {
+        let keys = oidc::keyring(issuer)?;
+        Ok(self.with_issuer(issuer, keys, policy))
+    }
+
+    fn with_scheme_issuer(
+        mut self,
+        scheme: Scheme,
+        issuer: String,
+        keys: Box<KeySource>,
+        policy: ValidationPolicy,
+    ) -> Self {
+        let policy = policy.issuer(issuer.clone());
+        let namespaced = scheme == Scheme::IdToken;
+        self.issuers.insert(issuer, Issuer { scheme, keys, policy, namespaced });
+        self
+    }
+
+    /// Decode and verify a Token with custom claims of type `C`
+    pub fn verify_token<T, C>(&self, token: T) -> Result<Token<C>>
     where
         T: Into<Cow<'static, str>>,
+        C: DeserializeOwned,
     {
-        // Token::decode(&token.into(), &self.key)
-        unimplemented!()
+        let token = token.into();
+        let header = Header::decode(&token)?;
+        let kid = header.kid.as_ref().map(String::as_str);
+
+        let (scheme, keys, policy, namespaced) = match Algorithm::from_name(&header.alg)?.scheme() {
+            Scheme::SharedSecret => match self.shared {
+                Some((ref secrets, ref policy)) => (Scheme::SharedSecret, secrets, policy, false),
+                None => bail!(ErrorKind::UnsupportedAlgorithm(header.alg.clone())),
+            },
+            // Issuers are told apart by the unverified `iss` claim:
+            // their policies make sure it's the issuer whose keys have verified the token
+            _ => {
+                let issuer = if self.issuers.is_empty() {
+                    None
+                } else {
+                    UnverifiedClaims::decode(&token)?.iss
+                };
+
+                match issuer.and_then(|iss| self.issuers.get(&iss)) {
+                    Some(issuer) => (issuer.scheme, &issuer.keys, &issuer.policy, issuer.namespaced),
+                    None => (Scheme::IdToken, &self.keys, &self.policy, false),
+                }
+            }
+        };
+
+        let key = keys.key(kid)?;
+        let mut token = Token::decode(&token, scheme, &key, policy)?;
+        if namespaced {
+            token.registered.uid = format!("{}|{}", token.registered.iss, token.registered.uid);
+        }
+        Ok(token)
     }
 }
 
-use futures::Future;
+use futures::{Async, Future, Poll};
+use futures::future::{self, Shared};
 use futures_cpupool::CpuPool;
 
+use json;
+use token::cache::{self, CacheStats, Digest, TokenCache, VerifiedToken};
+use token::revocation::RevocationList;
+use token::unix_now;
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::{Rc, Weak};
+
+/// Default number of verified tokens kept by an AsyncTokenVerifier
+const DEFAULT_CACHE_CAPACITY: usize = 10_000;
+
+/// Future verified token with custom claims left as raw JSON
+pub type FutureVerified = Box<Future<Item = VerifiedToken, Error = Error>>;
+
+/// Asynchronous token verification backend an `Authenticator` can be built on
+///
+/// Implementations live on the event loop thread
+pub trait Authority {
+    /// Verify a raw token
+    fn verified(&self, token: String) -> FutureVerified;
+}
+
 /// CpuPool driven token authentifier
+///
+/// Verified tokens are cached until they expire, and concurrent verifications
+/// of the same token are collapsed into one. Lives on the event loop thread
 pub struct AsyncTokenVerifier {
     cpupool: CpuPool,
     verifier: Arc<TokenVerifier>,
+    state: Rc<RefCell<CacheState>>,
+    revocations: Option<RevocationList>,
+}
+
+struct CacheState {
+    cache: TokenCache,
+    /// Verifications in progress with their waiters, dropped once nobody waits for them
+    in_flight: HashMap<Digest, (Shared<FutureVerified>, Weak<()>)>,
+    stats: CacheStats,
 }
 
 impl AsyncTokenVerifier {
     /// Constructs an AsyncTokenVerifier with a TokenVerifier
     /// and a CpuPool to run async verification tasks
-    pub fn new() -> Self {
-        let key = unimplemented!();
+    pub fn new(verifier: TokenVerifier) -> Self {
         AsyncTokenVerifier {
             cpupool: CpuPool::new_num_cpus(),
-            verifier: Arc::new(TokenVerifier::new(key)),
+            verifier: Arc::new(verifier),
+            state: Rc::new(RefCell::new(CacheState {
+                cache: TokenCache::new(DEFAULT_CACHE_CAPACITY),
+                in_flight: HashMap::new(),
+                stats: CacheStats::default(),
+            })),
+            revocations: None,
         }
     }
 
-    /// Asynchronously Verify JWT Token
-    pub fn authenticate(&self, token: String) -> impl Future<Item = Token, Error = Error> {
+    /// Set the number of verified tokens to keep. Zero disables caching
+    pub fn with_cache_capacity(self, capacity: usize) -> Self {
+        self.state.borrow_mut().cache = TokenCache::new(capacity);
+        self
+    }
+
+    /// Reject revoked tokens, including cached ones
+    pub fn with_revocation_list(mut self, revocations: RevocationList) -> Self {
+        self.revocations = Some(revocations);
+        self
+    }
+
+    /// Verified tokens cache hit and miss counters
+    pub fn cache_stats(&self) -> CacheStats {
+        self.state.borrow().stats
+    }
+
+    /// Asynchronously Verify JWT Token with custom claims of type `C`
+    pub fn authenticate<C>(&self, token: String) -> impl Future<Item = Token<C>, Error = Error>
+    where
+        C: DeserializeOwned + 'static,
+    {
+        self.verified(token).and_then(|token| token.typed())
+    }
+
+    fn cached_or_verify(&self, token: String, digest: Digest) -> FutureVerified {
+        let mut state = self.state.borrow_mut();
+
+        if let Some(token) = state.cache.get(&digest, unix_now()) {
+            state.stats.hits += 1;
+            return box future::ok(token);
+        }
+
+        let waiting = state.in_flight.get(&digest).and_then(|&(ref shared, ref waiters)| {
+            waiters.upgrade().map(|waiters| (shared.clone(), waiters))
+        });
+        if let Some((shared, waiters)) = waiting {
+            state.stats.collapsed += 1;
+            return box self.waiter(shared, waiters, digest);
+        }
+
+        state.stats.misses += 1;
+
         let verifier = self.verifier.clone();
-        self.cpupool.spawn_fn(move || verifier.verify_token(token))
+        let completed = self.state.clone();
+        let verification: FutureVerified = box self.cpupool
+            .spawn_fn(move || {
+                verifier.verify_token::<_, json::Value>(token).map(Arc::new)
+            })
+            .then(move |result| {
+                let mut state = completed.borrow_mut();
+                state.in_flight.remove(&digest);
+                if let Ok(ref token) = result {
+                    state.cache.insert(digest, token.clone());
+                }
+                result
+            });
+
+        let shared = verification.shared();
+        let waiters = Rc::new(());
+        state.in_flight.insert(digest, (shared.clone(), Rc::downgrade(&waiters)));
+        box self.waiter(shared, waiters, digest)
+    }
+
+    fn waiter(&self, shared: Shared<FutureVerified>, waiters: Rc<()>, digest: Digest) -> Waiter {
+        Waiter {
+            shared,
+            waiters: Some(waiters),
+            state: self.state.clone(),
+            digest,
+        }
+    }
+}
+
+impl Authority for AsyncTokenVerifier {
+    fn verified(&self, token: String) -> FutureVerified {
+        let digest = cache::digest(&token);
+        let revocations = self.revocations.clone();
+
+        box self.cached_or_verify(token, digest).and_then(move |token| {
+            if let Some(revocations) = revocations {
+                revocations.check(&digest, &token)?;
+            }
+            Ok(token)
+        })
+    }
+}
+
+/// Waiter of a shared verification, getting its own copy of the result
+///
+/// The last waiter dropped before the verification completes drops it too,
+/// as nothing would drive it to completion anymore
+struct Waiter {
+    shared: Shared<FutureVerified>,
+    waiters: Option<Rc<()>>,
+    state: Rc<RefCell<CacheState>>,
+    digest: Digest,
+}
+
+impl Future for Waiter {
+    type Item = VerifiedToken;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        match self.shared.poll() {
+            Ok(Async::Ready(token)) => Ok(Async::Ready((*token).clone())),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(e) => Err(Error::from(e.kind().duplicate())),
+        }
+    }
+}
+
+impl Drop for Waiter {
+    fn drop(&mut self) {
+        self.waiters.take();
+        let mut state = match self.state.try_borrow_mut() {
+            Ok(state) => state,
+            Err(_) => return,
+        };
+        let abandoned = match state.in_flight.get(&self.digest) {
+            Some(&(_, ref waiters)) => waiters.upgrade().is_none(),
+            None => false,
+        };
+        if abandoned {
+            state.in_flight.remove(&self.digest);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future::join_all;
+    use openssl::pkey::PKey;
+    use token::{Algorithm, NoClaims, StaticKeys, ValidationPolicy};
+    use token::stub::{encode, firebase_claims};
+    use tokio_core::reactor::Core;
+
+    fn verifier(key: &[u8]) -> AsyncTokenVerifier {
+        let keys = StaticKeys::new();
+        let secrets = StaticKeys::shared_secret(key).unwrap();
+        let verifier = TokenVerifier::new(keys, ValidationPolicy::new())
+            .with_shared_secrets(secrets, ValidationPolicy::new());
+        AsyncTokenVerifier::new(verifier)
+    }
+
+    #[test]
+    fn cache_and_collapse() {
+        let mut core = Core::new().unwrap();
+        let verifier = verifier(b"secret");
+        let key = PKey::hmac(b"secret").unwrap();
+        let token = encode(Algorithm::HS256, &key, &firebase_claims("12345"));
+
+        let concurrent = (0..3).map(|_| verifier.authenticate::<NoClaims>(token.clone()));
+        let tokens = core.run(join_all(concurrent.collect::<Vec<_>>())).unwrap();
+        assert!(tokens.iter().all(|token| token.user_id() == "12345"));
+
+        let expected = CacheStats {
+            hits: 0,
+            misses: 1,
+            collapsed: 2,
+        };
+        assert_eq!(verifier.cache_stats(), expected);
+
+        core.run(verifier.authenticate::<NoClaims>(token)).unwrap();
+        assert_eq!(verifier.cache_stats().hits, 1);
+    }
+
+    #[test]
+    fn errors_are_not_cached() {
+        let mut core = Core::new().unwrap();
+        let verifier = verifier(b"secret");
+        let key = PKey::hmac(b"another secret").unwrap();
+        let token = encode(Algorithm::HS256, &key, &firebase_claims("12345"));
+
+        for _ in 0..2 {
+            match *core.run(verifier.authenticate::<NoClaims>(token.clone()))
+                .unwrap_err()
+                .kind() {
+                ErrorKind::InvalidSignature => (),
+                ref e => panic!("unexpected error: {}", e),
+            }
+        }
+        assert_eq!(verifier.cache_stats().misses, 2);
+    }
+
+    #[test]
+    fn cancelled_verifications_are_forgotten() {
+        let mut core = Core::new().unwrap();
+        let verifier = verifier(b"secret");
+        let key = PKey::hmac(b"secret").unwrap();
+        let token = encode(Algorithm::HS256, &key, &firebase_claims("12345"));
+
+        // The only waiter gives up, i.e. the client disconnects
+        drop(verifier.authenticate::<NoClaims>(token.clone()));
+        assert!(verifier.state.borrow().in_flight.is_empty());
+
+        let verified = core.run(verifier.authenticate::<NoClaims>(token)).unwrap();
+        assert_eq!(verified.user_id(), "12345");
+        assert_eq!(verifier.cache_stats().misses, 2);
+        assert_eq!(verifier.cache_stats().collapsed, 0);
     }
 }